@@ -0,0 +1,210 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+use furiosa_metadata::debuginfo::{self, Consistency};
+use furiosa_metadata::doctor::{self, Severity};
+use furiosa_metadata::{diff, release, tuning};
+
+const USAGE: &str = "\
+Usage: furiosa-metadata <COMMAND>
+
+Commands:
+  check-debuginfo <BINARY> <DEBUGINFO> [<BINARY> <DEBUGINFO>...]
+      Checks that each binary and its separate debuginfo file come from the same build
+  check-tags [<TAG>...]
+      Checks that release tags agree with the package versions at the tagged commits,
+      reading the pushed refs of a pre-push hook if no tag is given
+  diff [--package <PACKAGE>] <BEFORE> <AFTER>
+      Shows the metadata fields changed between two builds, read from the stamp files,
+      the release manifests, the records, the summaries (of the package) or the binaries
+  doctor
+      Checks git, the repository and the FURIOSA_METADATA_* variables, and suggests fixes
+  install-hooks
+      Installs a pre-push hook running check-tags
+  patterns --interactive
+      Tries FURIOSA_METADATA_EXPECT_MODIFIED patterns against the updated files";
+
+const PATTERNS_HELP: &str = "\
+Enter a pattern to see which updated files it covers, or:
+  add <PATTERN>     Adds the pattern to FURIOSA_METADATA_EXPECT_MODIFIED
+  remove <PATTERN>  Removes the pattern
+  list              Shows the added patterns and the files they don't cover
+  done              Prints the variable with the added patterns and exits (also on EOF)";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["check-debuginfo", ref files @ ..] if !files.is_empty() && files.len() % 2 == 0 => {
+            check_debuginfo(files)
+        }
+        ["check-tags", ref tags @ ..] => check_tags(tags),
+        ["diff", before, after] => diff_builds(before, after, None),
+        ["diff", "--package", package, before, after] => diff_builds(before, after, Some(package)),
+        ["doctor"] => Ok(run_doctor()),
+        ["install-hooks"] => release::install_pre_push_hook().map(|path| {
+            println!("installed {}", path.display());
+            true
+        }),
+        ["patterns", "--interactive"] => tune_patterns(),
+        ["help" | "--help" | "-h"] => {
+            println!("{USAGE}");
+            Ok(true)
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns false if any pair is inconsistent.
+fn check_debuginfo(files: &[&str]) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut ok = true;
+    for pair in files.chunks(2) {
+        let (binary, debuginfo) = (pair[0], pair[1]);
+        match debuginfo::check(Path::new(binary), Path::new(debuginfo))? {
+            Consistency::Consistent(metadata) => println!("ok: {binary} ({metadata})"),
+            Consistency::Mismatched { binary: expected, debuginfo: actual } => {
+                println!("MISMATCH: {binary} ({expected}) vs. {debuginfo} ({actual})");
+                ok = false;
+            }
+            Consistency::MissingInBinary => {
+                match debuginfo::object_format(Path::new(binary))? {
+                    Some(format) => println!("UNSUPPORTED: {binary} is a {format} binary"),
+                    None => println!("MISSING: {binary} has no metadata note"),
+                }
+                ok = false;
+            }
+            Consistency::MissingInDebuginfo => {
+                println!("MISSING: {debuginfo} has no metadata note");
+                ok = false;
+            }
+        }
+    }
+    Ok(ok)
+}
+
+/// Returns false if any tag disagrees with the version.
+fn check_tags(tags: &[&str]) -> Result<bool, Box<dyn std::error::Error>> {
+    let input;
+    let tags: Vec<(&str, &str)> = if tags.is_empty() {
+        input = io::read_to_string(io::stdin())?;
+        release::pushed_tags(&input)
+    } else {
+        tags.iter().map(|&tag| (tag, tag)).collect()
+    };
+    let mut ok = true;
+    for (tag, object) in tags {
+        if let Err(e) = release::check_release_tag(tag, object) {
+            println!("error: {e}");
+            ok = false;
+        }
+    }
+    Ok(ok)
+}
+
+/// Returns false if any field has changed, like diff(1).
+fn diff_builds(
+    before: &str,
+    after: &str,
+    package: Option<&str>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let changes = diff::diff_files(Path::new(before), Path::new(after), package)?;
+    for change in &changes {
+        println!("{change}");
+    }
+    Ok(changes.is_empty())
+}
+
+/// Returns false if any check has failed.
+fn run_doctor() -> bool {
+    let diagnostics = doctor::diagnose();
+    for diagnostic in &diagnostics {
+        println!("{diagnostic}");
+    }
+    diagnostics.iter().all(|diagnostic| diagnostic.severity != Severity::Error)
+}
+
+/// Lets the user try patterns against the updated files until done,
+/// starting with the current `FURIOSA_METADATA_EXPECT_MODIFIED`.
+fn tune_patterns() -> Result<bool, Box<dyn std::error::Error>> {
+    let paths = tuning::updated_paths()?;
+    let mut added = tuning::expected_patterns()?;
+    println!("{} updated files:", paths.len());
+    for path in &paths {
+        println!("  {path}");
+    }
+    println!("\n{PATTERNS_HELP}\n");
+    print_patterns(&paths, &added)?;
+
+    let mut stdin = io::stdin().lock();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            println!();
+            break;
+        }
+        let line = line.trim();
+        match line.split_once(' ').map_or((line, ""), |(command, arg)| (command, arg.trim())) {
+            ("", _) => {}
+            ("done", "") => break,
+            ("list", "") => print_patterns(&paths, &added)?,
+            ("add", pattern) => match tuning::matching_paths(pattern, &paths) {
+                Ok(_) => {
+                    added.push(pattern.to_owned());
+                    print_patterns(&paths, &added)?;
+                }
+                Err(e) => println!("error: {e}"),
+            },
+            ("remove", pattern) => match added.iter().position(|p| p == pattern) {
+                Some(i) => {
+                    added.remove(i);
+                    print_patterns(&paths, &added)?;
+                }
+                None => println!("error: {pattern:?} is not added"),
+            },
+            _ => match tuning::matching_paths(line, &paths) {
+                Ok(matched) => {
+                    println!("{line} covers {} of {} files", matched.len(), paths.len());
+                    for path in matched {
+                        println!("  {path}");
+                    }
+                    if let Some(suggestion) = tuning::suggestion(line) {
+                        println!("hint: {suggestion}");
+                    }
+                }
+                Err(e) => println!("error: {e}"),
+            },
+        }
+    }
+
+    println!("\n{}", tuning::expect_modified_snippet(&added));
+    Ok(true)
+}
+
+/// Prints the added patterns, and the files not covered by any of them.
+fn print_patterns(paths: &[String], added: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut uncovered: Vec<&str> = paths.iter().map(String::as_str).collect();
+    for pattern in added {
+        let matched = tuning::matching_paths(pattern, paths)?;
+        println!("added: {pattern} (covers {})", matched.len());
+        uncovered.retain(|path| !matched.contains(path));
+    }
+    println!("{} files not covered:", uncovered.len());
+    for path in uncovered {
+        println!("  {path}");
+    }
+    Ok(())
+}
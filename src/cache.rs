@@ -0,0 +1,193 @@
+//! Shares the git metadata between build scripts, so that a large workspace runs git once
+//! instead of once per crate.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use chrono::{DateTime, Utc};
+
+use crate::gitdir::GitDir;
+use crate::patterns::Policy;
+use crate::{
+    discover_git_dir, format_timestamp, invocation_id, json, short_digest, target_dir,
+    write_atomically, GitDetails, ResolvedHash, Source,
+};
+
+pub(crate) const CACHE_VAR: &str = "FURIOSA_METADATA_CACHE";
+
+/// The cached git metadata of a repository, either
+///
+/// * shared in the current cargo invocation, i.e.
+///   `<target dir>/furiosa-metadata/invocation/<invocation id>/<repository>.json`, or
+/// * shared while the repository stays the same (warm), i.e.
+///   `<target dir>/furiosa-metadata/warm/<repository>.json`.
+pub(crate) struct GitCache {
+    path: PathBuf,
+    /// The state of the repository for the warm cache, which must match to use the cached values.
+    state: Option<String>,
+}
+
+impl GitCache {
+    /// Returns the cache if enabled by `FURIOSA_METADATA_CACHE`, which is `1` for the invocation
+    /// cache and `warm` for the warm cache.
+    pub(crate) fn open() -> Result<Option<GitCache>, Box<dyn std::error::Error>> {
+        println!("cargo:rerun-if-env-changed={CACHE_VAR}");
+        let warm = match env::var(CACHE_VAR).as_deref() {
+            Ok("1") => false,
+            Ok("warm") => true,
+            _ => return Ok(None),
+        };
+        let git_dir = match discover_git_dir() {
+            Ok(Some(git_dir)) => git_dir,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                eprintln!("[furiosa-metadata] {CACHE_VAR} is ignored: {e}");
+                return Ok(None);
+            }
+        };
+
+        // git dependencies are built from other repositories
+        let repository = short_digest(&[&git_dir.git_dir.to_string_lossy()]);
+        let dir = target_dir()?.join("furiosa-metadata");
+        if warm {
+            let state = match repository_state(&git_dir) {
+                Ok(state) => state,
+                Err(e) => {
+                    eprintln!("[furiosa-metadata] {CACHE_VAR} is ignored: {e}");
+                    return Ok(None);
+                }
+            };
+            let path = dir.join("warm").join(format!("{repository}.json"));
+            return Ok(Some(GitCache { path, state: Some(state) }));
+        }
+
+        let Some(invocation_id) = invocation_id() else {
+            eprintln!("[furiosa-metadata] {CACHE_VAR} is ignored as the invocation is unknown.");
+            return Ok(None);
+        };
+        let path = dir.join("invocation").join(invocation_id).join(format!("{repository}.json"));
+        Ok(Some(GitCache { path, state: None }))
+    }
+
+    /// Returns the cached metadata, or `None` if not cached yet.
+    pub(crate) fn load(&self) -> Option<(ResolvedHash, GitDetails)> {
+        let value = json::parse(&fs::read_to_string(&self.path).ok()?).ok()?;
+        let string = |name: &str| value.get(name).and_then(json::Value::as_str).map(str::to_owned);
+        if string("state") != self.state {
+            return None;
+        }
+
+        let dirty = value.get("dirty").and_then(json::Value::as_bool);
+        let hash = ResolvedHash {
+            short_hash: string("short_hash")?,
+            full_hash: None,
+            dirty,
+            hash_source: Source::Git,
+            dirty_source: if dirty.is_some() { Source::Git } else { Source::Skipped },
+        };
+        let details = GitDetails {
+            hash: string("hash")?,
+            branch: string("branch"),
+            describe: string("describe")?,
+            commit_time: DateTime::parse_from_rfc3339(&string("commit_time")?)
+                .ok()?
+                .with_timezone(&Utc),
+        };
+        Some((hash, details))
+    }
+
+    /// Caches the metadata resolved by git.
+    pub(crate) fn store(
+        &self,
+        hash: &ResolvedHash,
+        details: &GitDetails,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let value = json::Value::Object(vec![
+            ("state".to_owned(), self.state.as_deref().into()),
+            ("short_hash".to_owned(), hash.short_hash.as_str().into()),
+            ("dirty".to_owned(), hash.dirty.into()),
+            ("hash".to_owned(), details.hash.as_str().into()),
+            ("branch".to_owned(), details.branch.as_deref().into()),
+            ("describe".to_owned(), details.describe.as_str().into()),
+            ("commit_time".to_owned(), format_timestamp(&details.commit_time).into()),
+        ]);
+
+        let dir = self.path.parent().ok_or("bad cache path")?;
+        if self.state.is_none() && !dir.exists() {
+            // the first build script in this invocation cleans up the previous invocations
+            if let Some(parent) = dir.parent() {
+                for entry in fs::read_dir(parent).into_iter().flatten().flatten() {
+                    if Some(entry.file_name().as_os_str()) != dir.file_name() {
+                        let _ = fs::remove_dir_all(entry.path());
+                    }
+                }
+            }
+        }
+        fs::create_dir_all(dir)?;
+
+        write_atomically(&self.path, &value.to_string())
+    }
+}
+
+/// Returns a digest of everything the git metadata depends on, except for modifications not yet
+/// added to the index, which git can only find by scanning the working tree.
+fn repository_state(git_dir: &GitDir) -> Result<String, Box<dyn std::error::Error>> {
+    let mut parts = vec![git_dir.head_commit()?.unwrap_or_default()];
+    for path in git_dir.watched_paths()? {
+        parts.push(path.display().to_string());
+        parts.push(modification_time(&path)?);
+    }
+    for policy in Policy::ALL {
+        parts.push(env::var(policy.var()).unwrap_or_default());
+    }
+    let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+    Ok(short_digest(&parts))
+}
+
+fn modification_time(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let metadata = fs::metadata(path)?;
+    let time = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok(format!("{}.{:09} {}", time.as_secs(), time.subsec_nanos(), metadata.len()))
+}
+
+#[test]
+fn round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = env::temp_dir().join(format!("furiosa-metadata-cache-{}", std::process::id()));
+    let cache = GitCache { path: dir.join("invocation").join("repository.json"), state: None };
+    assert!(cache.load().is_none());
+
+    let hash = ResolvedHash {
+        short_hash: "0123456789-modified".to_owned(),
+        full_hash: None,
+        dirty: Some(true),
+        hash_source: Source::Git,
+        dirty_source: Source::Git,
+    };
+    let details = GitDetails {
+        hash: "0123456789abcdef0123456789abcdef01234567".to_owned(),
+        branch: None,
+        describe: "v1.0.0-1-g0123456789".to_owned(),
+        commit_time: DateTime::parse_from_rfc3339("2025-01-07T10:00:00Z")?.with_timezone(&Utc),
+    };
+    cache.store(&hash, &details)?;
+    let (loaded_hash, loaded_details) = cache.load().ok_or("not cached")?;
+
+    let warm_path = dir.join("warm").join("repository.json");
+    let warm_cache = GitCache { path: warm_path.clone(), state: Some("before".to_owned()) };
+    warm_cache.store(&hash, &details)?;
+    let warm_loaded = warm_cache.load().is_some();
+    let changed_loaded = GitCache { path: warm_path, state: Some("after".to_owned()) }.load();
+    fs::remove_dir_all(&dir)?;
+
+    assert!(warm_loaded);
+    assert!(changed_loaded.is_none());
+
+    assert_eq!((loaded_hash.short_hash, loaded_hash.dirty), (hash.short_hash, hash.dirty));
+    assert_eq!(loaded_details.hash, details.hash);
+    assert_eq!(loaded_details.branch, None);
+    assert_eq!(loaded_details.describe, details.describe);
+    assert_eq!(loaded_details.commit_time, details.commit_time);
+    Ok(())
+}
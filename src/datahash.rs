@@ -0,0 +1,100 @@
+//! Hashing of asset directories for `FURIOSA_DATA_HASH`.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::{HashAlgorithm, Hasher};
+
+/// Returns a fingerprint of the relative paths and contents of all files in `dirs`.
+///
+/// Symbolic links are hashed by their targets, as in git, instead of being followed.
+/// Files are hashed in parallel with the `rayon` feature.
+pub(crate) fn hash_dirs(
+    dirs: &[PathBuf],
+    algorithm: HashAlgorithm,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut parts = Vec::new();
+    for dir in dirs {
+        let mut files = Vec::new();
+        list_files(dir, &mut files)
+            .map_err(|e| format!("Failed to list {}: {e}", dir.display()))?;
+        files.sort();
+
+        for (path, digest) in files.iter().zip(hash_files(&files, algorithm)?) {
+            parts.push(path.strip_prefix(dir).unwrap_or(path).to_string_lossy().into_owned());
+            parts.push(digest);
+        }
+        // keeps `a/` + `b/c` apart from `a/b/` + `c`
+        parts.push(String::new());
+    }
+    let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+    Ok(algorithm.fingerprint(&parts))
+}
+
+#[cfg(feature = "rayon")]
+fn hash_files(files: &[PathBuf], algorithm: HashAlgorithm) -> Result<Vec<String>, String> {
+    use rayon::prelude::*;
+
+    files.par_iter().map(|path| hash_file(path, algorithm)).collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+fn hash_files(files: &[PathBuf], algorithm: HashAlgorithm) -> Result<Vec<String>, String> {
+    files.iter().map(|path| hash_file(path, algorithm)).collect()
+}
+
+/// Returns the digest of a file, streamed to keep memory usage bounded for large files.
+fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<String, String> {
+    let hash = || -> io::Result<_> {
+        let mut hasher = Hasher::new(algorithm);
+        if fs::symlink_metadata(path)?.file_type().is_symlink() {
+            hasher.update(fs::read_link(path)?.to_string_lossy().as_bytes());
+        } else {
+            let mut file = File::open(path)?;
+            let mut buf = vec![0; 1 << 16];
+            loop {
+                match file.read(&mut buf)? {
+                    0 => break,
+                    len => hasher.update(&buf[..len]),
+                }
+            }
+        }
+        Ok(hasher.finalize_hex())
+    };
+    hash().map_err(|e| format!("Failed to hash {}: {e}", path.display()))
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn data_hash() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("furiosa-metadata-data-{}", std::process::id()));
+    fs::create_dir_all(dir.join("firmware"))?;
+    fs::write(dir.join("firmware/a.bin"), [0u8; 100_000])?;
+    fs::write(dir.join("b.txt"), "b")?;
+    let hash = |dir: &PathBuf| hash_dirs(&[dir.clone()], HashAlgorithm::Sha256);
+    let before = hash(&dir)?;
+    let same = hash(&dir)?;
+    fs::write(dir.join("b.txt"), "c")?;
+    let modified = hash(&dir)?;
+    fs::rename(dir.join("b.txt"), dir.join("firmware/b.txt"))?;
+    let moved = hash(&dir)?;
+    fs::remove_dir_all(&dir)?;
+
+    assert!(before.starts_with("sha256:"));
+    assert_eq!(before, same);
+    assert_ne!(before, modified);
+    assert_ne!(modified, moved);
+    Ok(())
+}
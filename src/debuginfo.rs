@@ -0,0 +1,205 @@
+//! Consistency checks between binaries and their separate debuginfo files.
+//!
+//! Separate debuginfo files (as produced by `objcopy --only-keep-debug`) drop the contents of
+//! all allocated sections except notes, so the metadata constants themselves are not there.
+//! Instead [`metadata_note!`](crate::metadata_note) embeds the metadata into an ELF note,
+//! which is kept in both files and can be compared before uploading them to the symbol server.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The prefix of the note descriptor, followed by the metadata and a NUL.
+const MARKER: &[u8] = b"furiosa-metadata:";
+
+/// The note name, see [`Note`].
+const NOTE_NAME: &[u8; 8] = b"FURIOSA\0";
+
+/// An ELF note section content, generated by [`metadata_note!`](crate::metadata_note).
+///
+/// `N` should be [`note_len`] of the descriptor.
+#[doc(hidden)]
+#[repr(C, align(4))]
+pub struct Note<const N: usize>([u8; N]);
+
+impl<const N: usize> Note<N> {
+    #[doc(hidden)]
+    pub const fn new(desc: &str) -> Self {
+        let desc = desc.as_bytes();
+        let mut note = [0u8; N];
+        let header = [NOTE_NAME.len() as u32, desc.len() as u32 + 1, 1];
+        let mut i = 0;
+        while i < header.len() {
+            let bytes = header[i].to_ne_bytes();
+            let mut j = 0;
+            while j < 4 {
+                note[i * 4 + j] = bytes[j];
+                j += 1;
+            }
+            i += 1;
+        }
+        i = 0;
+        while i < NOTE_NAME.len() {
+            note[12 + i] = NOTE_NAME[i];
+            i += 1;
+        }
+        i = 0;
+        while i < desc.len() {
+            note[12 + NOTE_NAME.len() + i] = desc[i];
+            i += 1;
+        }
+        Note(note)
+    }
+}
+
+/// Returns the size of a note containing `desc` followed by a NUL, padded to 4 bytes.
+#[doc(hidden)]
+pub const fn note_len(desc: &str) -> usize {
+    12 + NOTE_NAME.len() + (desc.len() + 1 + 3) / 4 * 4
+}
+
+/// Embeds the build metadata into an ELF note, so that it survives in separate debuginfo files.
+///
+/// This requires [`set_metadata_env_vars`](crate::set_metadata_env_vars) in the build script,
+/// and should be invoked once in the binary (or cdylib) crate. The note can be read back with
+/// [`debuginfo::read_metadata_note`](crate::debuginfo::read_metadata_note).
+///
+/// Only ELF targets are supported, with separate `.debug` files. Nothing is embedded for
+/// Apple targets (so dSYM bundles can't be checked) or Windows, and such binaries are reported
+/// as having no metadata note.
+#[macro_export]
+macro_rules! metadata_note {
+    () => {
+        const _: () = {
+            const DESC: &str = concat!(
+                "furiosa-metadata:",
+                env!("CARGO_PKG_VERSION"),
+                " ",
+                env!("FURIOSA_GIT_SHORT_HASH"),
+                " ",
+                env!("FURIOSA_BUILD_TIMESTAMP"),
+            );
+            #[cfg(not(any(target_vendor = "apple", windows)))]
+            #[used]
+            #[link_section = ".note.furiosa.metadata"]
+            static NOTE: $crate::debuginfo::Note<{ $crate::debuginfo::note_len(DESC) }> =
+                $crate::debuginfo::Note::new(DESC);
+        };
+    };
+}
+
+/// Reads the metadata embedded by [`metadata_note!`](crate::metadata_note) from given file,
+/// which is `VERSION GIT_SHORT_HASH BUILD_TIMESTAMP`.
+///
+/// Returns `None` if there is no metadata note, which is always the case for a Mach-O or PE file
+/// (see [`object_format`]).
+pub fn read_metadata_note(path: &Path) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {e}", path.display()))?;
+    find_metadata_note(file).map_err(|e| format!("Failed to read {}: {e}", path.display()).into())
+}
+
+/// Returns the object format of a file, if it is a Mach-O or PE file, which never has
+/// a metadata note as [`metadata_note!`](crate::metadata_note) only supports ELF.
+pub fn object_format(path: &Path) -> Result<Option<&'static str>, Box<dyn std::error::Error>> {
+    let mut magic = [0; 4];
+    let mut file =
+        File::open(path).map_err(|e| format!("Failed to open {}: {e}", path.display()))?;
+    let n = file.read(&mut magic).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+    Ok(match &magic[..n] {
+        [0xfe, 0xed, 0xfa, 0xce | 0xcf] | [0xce | 0xcf, 0xfa, 0xed, 0xfe] => Some("Mach-O"),
+        [0xca, 0xfe, 0xba, 0xbe] => Some("Mach-O universal"),
+        [b'M', b'Z', ..] => Some("PE"),
+        _ => None,
+    })
+}
+
+/// Scans `reader` for the metadata note without reading the whole (possibly huge) file at once.
+fn find_metadata_note(mut reader: impl Read) -> io::Result<Option<String>> {
+    const CHUNK_SIZE: usize = 1 << 20;
+    const MAX_DESC_LEN: usize = 4096;
+
+    // matching the note name as well avoids false positives from a stray marker string
+    let needle = [&NOTE_NAME[..], MARKER].concat();
+    let mut buf = Vec::with_capacity(CHUNK_SIZE + MAX_DESC_LEN);
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut chunk)?;
+        buf.extend_from_slice(&chunk[..n]);
+
+        if let Some(start) = buf.windows(needle.len()).position(|w| w == needle) {
+            let desc = &buf[start + needle.len()..];
+            match desc.iter().position(|&c| c == 0) {
+                Some(end) => return Ok(Some(String::from_utf8_lossy(&desc[..end]).into_owned())),
+                None if n == 0 || desc.len() > MAX_DESC_LEN => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated metadata"));
+                }
+                None => continue, // the rest of the note is in the next chunk
+            }
+        }
+
+        if n == 0 {
+            return Ok(None);
+        }
+        // keep the tail, which may contain a part of the needle
+        let keep = buf.len().min(needle.len() - 1);
+        buf.drain(..buf.len() - keep);
+    }
+}
+
+/// The result of [`check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Consistency {
+    /// Both files have the same metadata.
+    Consistent(String),
+    /// The files have different metadata, so they come from different builds.
+    Mismatched { binary: String, debuginfo: String },
+    /// The binary doesn't have the metadata note.
+    MissingInBinary,
+    /// The debuginfo doesn't have the metadata note, e.g. the note was stripped.
+    MissingInDebuginfo,
+}
+
+/// Compares the metadata notes embedded in a binary and its separate debuginfo file.
+pub fn check(binary: &Path, debuginfo: &Path) -> Result<Consistency, Box<dyn std::error::Error>> {
+    Ok(match (read_metadata_note(binary)?, read_metadata_note(debuginfo)?) {
+        (None, _) => Consistency::MissingInBinary,
+        (Some(_), None) => Consistency::MissingInDebuginfo,
+        (Some(binary), Some(debuginfo)) if binary == debuginfo => Consistency::Consistent(binary),
+        (Some(binary), Some(debuginfo)) => Consistency::Mismatched { binary, debuginfo },
+    })
+}
+
+#[test]
+fn note() -> io::Result<()> {
+    const DESC: &str = "furiosa-metadata:0.2.0 0123456789-modified 2025-01-07T10:00:00Z";
+    const NOTE: Note<{ note_len(DESC) }> = Note::new(DESC);
+    assert_eq!(NOTE.0.len() % 4, 0);
+    assert_eq!(&NOTE.0[12..20], NOTE_NAME);
+
+    // the marker crossing a chunk boundary should be found as well
+    let mut file = vec![0xcc; (1 << 20) - 5];
+    file.extend_from_slice(&NOTE.0);
+    file.extend_from_slice(&[0xcc; 100]);
+    let expected = "0.2.0 0123456789-modified 2025-01-07T10:00:00Z";
+    assert_eq!(find_metadata_note(&file[..])?.as_deref(), Some(expected));
+    assert_eq!(find_metadata_note(&file[..1000])?, None);
+    Ok(())
+}
+
+#[test]
+fn object_formats() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("furiosa-metadata-formats-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let formats = [&b"\x7fELF"[..], b"\xcf\xfa\xed\xfe", b"MZ\x90\x00", b""]
+        .iter()
+        .enumerate()
+        .map(|(i, magic)| {
+            let path = dir.join(i.to_string());
+            std::fs::write(&path, magic)?;
+            object_format(&path)
+        })
+        .collect::<Result<Vec<_>, _>>();
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(formats?, [None, Some("Mach-O"), Some("PE"), None]);
+    Ok(())
+}
@@ -0,0 +1,306 @@
+//! Differences between the metadata of two builds, e.g. for `furiosa-metadata diff`, so that
+//! a behavior change between builds can be annotated with what changed in the build environment.
+//!
+//! The metadata is read from any of the files written by
+//! [`set_metadata_env_vars`](crate::set_metadata_env_vars), i.e. the stamp file
+//! `$OUT_DIR/furiosa-metadata.env`, the release manifest, the records of
+//! `FURIOSA_METADATA_TIMINGS` and the summary (one of its crates, by the package name if it
+//! lists several), or from a binary with [`metadata_note!`](crate::metadata_note). Fields are
+//! named after the variables without `FURIOSA_` (e.g. `GIT_SHORT_HASH`), with `VERSION` and
+//! `PACKAGE` from the package.
+//! A binary only has `VERSION`, `GIT_SHORT_HASH` and `BUILD_TIMESTAMP`, so compare it with
+//! another binary rather than a file.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::{debuginfo, json, parse_stamp};
+
+/// How much a changed field matters, from the least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Differs between any two builds of the same source, e.g. the build timestamp.
+    Info,
+    /// The source is different, e.g. the commit, which is expected to change the behavior.
+    Source,
+    /// The build environment is different, e.g. the rustc version, which may change
+    /// the behavior of the same source.
+    Environment,
+}
+
+impl Severity {
+    /// Returns the severity of a change of `field`. Unknown fields, e.g. of
+    /// [`MetadataOptions::command_field`](crate::MetadataOptions::command_field), usually record
+    /// tool versions, so they are [`Severity::Environment`].
+    pub fn of(field: &str) -> Severity {
+        match field {
+            "BUILD_TIMESTAMP"
+            | "BUILD_TIMESTAMP_BASIC"
+            | "BUILD_TIMESTAMP_FILENAME"
+            | "BUILD_INVOCATION_ID"
+            | "BUILD_CACHE"
+            | "BUILDER_FINGERPRINT"
+            | "CHANGED_COMPONENTS"
+            | "GIT_BRANCH"
+            | "METADATA_SOURCE"
+            | "METADATA_TRUNCATED"
+            | "PACKAGE" => Severity::Info,
+            "VERSION" | "GIT_SHORT_HASH" | "GIT_HASH" | "GIT_DESCRIBE" | "GIT_COMMIT_DATE"
+            | "GIT_DIRTY" | "DATA_HASH" | "SYMBOL_VERSION" => Severity::Source,
+            _ => Severity::Environment,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Source => "source",
+            Severity::Environment => "environment",
+        }
+    }
+}
+
+/// A change of a field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// Only in the newer build.
+    Added(String),
+    /// Only in the older build.
+    Removed(String),
+    Changed {
+        before: String,
+        after: String,
+    },
+}
+
+/// A changed field, see [`diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: String,
+    pub change: Change,
+    pub severity: Severity,
+}
+
+/// Formats the change in a single line, e.g. `~ RUSTC_VERSION: 1.75.0 -> 1.76.0 (environment)`.
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let field = &self.field;
+        match &self.change {
+            Change::Added(value) => write!(f, "+ {field}: {value}")?,
+            Change::Removed(value) => write!(f, "- {field}: {value}")?,
+            Change::Changed { before, after } => write!(f, "~ {field}: {before} -> {after}")?,
+        }
+        write!(f, " ({})", self.severity.name())
+    }
+}
+
+/// Returns the changed fields from `before` to `after`, sorted by the field name.
+pub fn diff(
+    before: &BTreeMap<String, String>,
+    after: &BTreeMap<String, String>,
+) -> Vec<FieldChange> {
+    let mut fields: Vec<&String> = before.keys().chain(after.keys()).collect();
+    fields.sort();
+    fields.dedup();
+    fields
+        .into_iter()
+        .filter_map(|field| {
+            let change = match (before.get(field), after.get(field)) {
+                (Some(before), Some(after)) if before == after => return None,
+                (Some(before), Some(after)) => {
+                    Change::Changed { before: before.clone(), after: after.clone() }
+                }
+                (None, Some(after)) => Change::Added(after.clone()),
+                (Some(before), None) => Change::Removed(before.clone()),
+                (None, None) => return None,
+            };
+            Some(FieldChange { field: field.clone(), change, severity: Severity::of(field) })
+        })
+        .collect()
+}
+
+/// Same as [`diff`], but reads the fields from files with [`read_fields`].
+pub fn diff_files(
+    before: &Path,
+    after: &Path,
+    package: Option<&str>,
+) -> Result<Vec<FieldChange>, Box<dyn std::error::Error>> {
+    Ok(diff(&read_fields(before, package)?, &read_fields(after, package)?))
+}
+
+/// Reads the fields from a file written by this crate, or from a binary.
+///
+/// `package` picks the crate from a summary, which is required if it lists several crates,
+/// and is ignored for other files. Fails if there is no metadata in the file.
+pub fn read_fields(
+    path: &Path,
+    package: Option<&str>,
+) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    let contents = fs::read(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+    let fields = match std::str::from_utf8(&contents) {
+        Ok(s) if s.trim_start().starts_with('{') => {
+            let value =
+                json::parse(s).map_err(|e| format!("Failed to parse {}: {e}", path.display()))?;
+            match value.get("crates").and_then(json::Value::as_array) {
+                Some(crates) => json_fields(summary_record(crates, package, path)?),
+                None => json_fields(&value),
+            }
+        }
+        Ok(s) if s.starts_with("FURIOSA_") => parse_stamp(s)?
+            .into_iter()
+            .filter_map(|(name, value)| Some((name.strip_prefix("FURIOSA_")?.to_owned(), value)))
+            .collect(),
+        _ => {
+            debuginfo::read_metadata_note(path)?.map(|note| note_fields(&note)).unwrap_or_default()
+        }
+    };
+    if let (true, Some(format)) = (fields.is_empty(), debuginfo::object_format(path)?) {
+        return Err(format!(
+            "No metadata in {}, as metadata_note! doesn't support {format} binaries",
+            path.display()
+        )
+        .into());
+    }
+    if fields.is_empty() {
+        return Err(format!("No metadata in {}", path.display()).into());
+    }
+    Ok(fields)
+}
+
+/// Returns the record of `package` in the `crates` of a summary, or the only one.
+fn summary_record<'a>(
+    crates: &'a [json::Value],
+    package: Option<&str>,
+    path: &Path,
+) -> Result<&'a json::Value, Box<dyn std::error::Error>> {
+    let records: Vec<&json::Value> =
+        crates.iter().filter(|record| package.map_or(true, |p| name(record) == Some(p))).collect();
+    match (&records[..], package) {
+        ([record], _) => Ok(record),
+        ([], Some(package)) => {
+            Err(format!("No crate {package} in the summary {}", path.display()).into())
+        }
+        ([], None) => Err(format!("No crate in the summary {}", path.display()).into()),
+        (_, Some(package)) => Err(format!(
+            "Several crates {package} in the summary {}, e.g. with other features",
+            path.display()
+        )
+        .into()),
+        (records, None) => {
+            let names: Vec<&str> = records.iter().filter_map(|record| name(record)).collect();
+            Err(format!(
+                "The summary {} lists several crates, so pick one of {}",
+                path.display(),
+                names.join(", ")
+            )
+            .into())
+        }
+    }
+}
+
+/// Returns the package of a record.
+fn name(record: &json::Value) -> Option<&str> {
+    record.get("package").and_then(json::Value::as_str)
+}
+
+/// Returns the fields of the release manifest or a record with `vars`.
+fn json_fields(value: &json::Value) -> BTreeMap<String, String> {
+    let mut fields = BTreeMap::new();
+    for (key, field) in [
+        ("name", "PACKAGE"),
+        ("package", "PACKAGE"),
+        ("version", "VERSION"),
+        ("git_short_hash", "GIT_SHORT_HASH"),
+        ("channel", "CHANNEL"),
+        ("build_timestamp", "BUILD_TIMESTAMP"),
+    ] {
+        if let Some(value) = value.get(key).and_then(json::Value::as_str) {
+            fields.insert(field.to_owned(), value.to_owned());
+        }
+    }
+    if let Some(json::Value::Object(vars)) = value.get("vars") {
+        for (name, value) in vars {
+            if let (Some(name), Some(value)) = (name.strip_prefix("FURIOSA_"), value.as_str()) {
+                fields.insert(name.to_owned(), value.to_owned());
+            }
+        }
+    }
+    fields
+}
+
+/// Returns the fields of a metadata note, `VERSION GIT_SHORT_HASH BUILD_TIMESTAMP`.
+fn note_fields(note: &str) -> BTreeMap<String, String> {
+    ["VERSION", "GIT_SHORT_HASH", "BUILD_TIMESTAMP"]
+        .into_iter()
+        .zip(note.split(' '))
+        .map(|(field, value)| (field.to_owned(), value.to_owned()))
+        .collect()
+}
+
+#[test]
+fn diffs() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("furiosa-metadata-diff-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let stamp = dir.join("furiosa-metadata.env");
+    fs::write(
+        &stamp,
+        "FURIOSA_GIT_SHORT_HASH=0123456789\n\
+         FURIOSA_BUILD_TIMESTAMP=2025-01-07T10:00:00Z\n\
+         FURIOSA_RUSTC_VERSION=rustc 1.75.0\n\
+         FURIOSA_BUILD_PROFILE=release\n",
+    )?;
+    let record = dir.join("record.json");
+    fs::write(
+        &record,
+        r#"{"package": "rt", "version": "1.2.3", "vars": {
+            "FURIOSA_GIT_SHORT_HASH": "0123456789",
+            "FURIOSA_BUILD_TIMESTAMP": "2025-01-08T10:00:00Z",
+            "FURIOSA_RUSTC_VERSION": "rustc 1.76.0",
+            "FURIOSA_DATA_HASH": "sha256:0123456789abcdef"
+        }}"#,
+    )?;
+    let empty = dir.join("empty");
+    fs::write(&empty, b"\x7fELF")?;
+    let mach_o = dir.join("mach-o");
+    fs::write(&mach_o, b"\xcf\xfa\xed\xfe")?;
+    let summary = dir.join("summary.json");
+    let crates = format!(
+        r#"{{"crates": [{}, {{"package": "cli", "vars": {{}}}}]}}"#,
+        fs::read_to_string(&record)?
+    );
+    fs::write(&summary, crates)?;
+    let changes = diff_files(&stamp, &record, None);
+    let no_metadata = read_fields(&empty, None).is_err();
+    let unsupported = read_fields(&mach_o, None).map_err(|e| e.to_string());
+    let from_summary = read_fields(&summary, Some("rt"));
+    let ambiguous = read_fields(&summary, None).map_err(|e| e.to_string());
+    let from_record = read_fields(&record, None);
+    fs::remove_dir_all(&dir)?;
+
+    let lines: Vec<String> = changes?.iter().map(ToString::to_string).collect();
+    assert_eq!(
+        lines,
+        [
+            "- BUILD_PROFILE: release (environment)",
+            "~ BUILD_TIMESTAMP: 2025-01-07T10:00:00Z -> 2025-01-08T10:00:00Z (info)",
+            "+ DATA_HASH: sha256:0123456789abcdef (source)",
+            "+ PACKAGE: rt (info)",
+            "~ RUSTC_VERSION: rustc 1.75.0 -> rustc 1.76.0 (environment)",
+            "+ VERSION: 1.2.3 (source)",
+        ]
+    );
+    assert!(no_metadata);
+    assert_eq!(from_summary?, from_record?);
+    assert!(ambiguous.unwrap_err().contains("pick one of rt, cli"));
+    assert!(unsupported.unwrap_err().contains("doesn't support Mach-O binaries"));
+
+    assert_eq!(
+        note_fields("1.2.3 0123456789-modified 2025-01-07T10:00:00Z")["GIT_SHORT_HASH"],
+        "0123456789-modified"
+    );
+    assert_eq!(Severity::of("FIRMWARE_VERSION"), Severity::Environment);
+    assert!(Severity::Info < Severity::Environment);
+    Ok(())
+}
@@ -0,0 +1,343 @@
+//! Checks the prerequisites of [`set_metadata_env_vars`](crate::set_metadata_env_vars)
+//! in the current environment, e.g. for `furiosa-metadata doctor`.
+
+use std::env;
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+use crate::hash::parse_git_hash;
+use crate::patterns::{lint_pattern, read_policy_patterns};
+use crate::{
+    cache, is_valid_channel, non_empty_var, parse_clock_skew, resolve_build_timestamp, run_git,
+    BUILDER_SALT_VAR, CEILING_VAR, CHANNEL_VAR, CLOCK_SKEW_VAR, DETERMINISTIC, DETERMINISTIC_VAR,
+    NO_GIT_RERUN_VAR, OFFLINE_VAR, PLACEHOLDER_VAR, PREVIOUS_HASH_VAR, TIMESTAMP_OVERRIDE_VAR,
+    TIMINGS_VAR,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    /// Works, but probably not as intended.
+    Warning,
+    /// Fails the build.
+    Error,
+}
+
+/// The result of a single check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// What was checked, e.g. `git`.
+    pub subject: &'static str,
+    pub message: String,
+    /// How to fix the problem, if any.
+    pub fix: Option<String>,
+}
+
+impl Diagnostic {
+    fn ok(subject: &'static str, message: impl Into<String>) -> Self {
+        Diagnostic { severity: Severity::Ok, subject, message: message.into(), fix: None }
+    }
+
+    fn warning(subject: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        let (message, fix) = (message.into(), Some(fix.into()));
+        Diagnostic { severity: Severity::Warning, subject, message, fix }
+    }
+
+    fn error(subject: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        let (message, fix) = (message.into(), Some(fix.into()));
+        Diagnostic { severity: Severity::Error, subject, message, fix }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{severity}: {}: {}", self.subject, self.message)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n  fix: {fix}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks git, the repository, the `FURIOSA_METADATA_*_MODIFIED` patterns and
+/// the other environment variables, as seen from the current directory.
+pub fn diagnose() -> Vec<Diagnostic> {
+    let mut diagnostics = vec![check_git()];
+    if diagnostics[0].severity != Severity::Error {
+        diagnostics.push(check_repository());
+    }
+    diagnostics.push(check_patterns());
+    diagnostics.extend(check_vars(|name| non_empty_var(name).ok().flatten()));
+    diagnostics
+}
+
+fn check_git() -> Diagnostic {
+    let output = match Command::new("git").arg("--version").output() {
+        Ok(output) => output,
+        Err(e) => {
+            return Diagnostic::error(
+                "git",
+                format!("failed to run git: {e}"),
+                "install git, or use `MetadataOptions::fallback_hash_file` \
+                 or `FURIOSA_GIT_SHORT_HASH` where git is not available",
+            )
+        }
+    };
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    match parse_git_version(&version) {
+        // `git status --porcelain=v2` and `GIT_OPTIONAL_LOCKS`
+        Some(parsed) if parsed < (2, 15) => Diagnostic::warning(
+            "git",
+            format!("{version} is too old, and may fail or modify the index while building"),
+            "upgrade git to 2.15 or later",
+        ),
+        Some(_) => Diagnostic::ok("git", version),
+        None => Diagnostic::warning(
+            "git",
+            format!("unexpected version {version:?}"),
+            "check that `git` in PATH is the real git",
+        ),
+    }
+}
+
+fn parse_git_version(version: &str) -> Option<(u32, u32)> {
+    let version = version.strip_prefix("git version ")?;
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+fn check_repository() -> Diagnostic {
+    match run_git(&["rev-parse", "--show-toplevel"], |s| Ok::<_, &str>(s.trim().to_owned())) {
+        Ok(toplevel) => {
+            match run_git(&["rev-parse", "--verify", "--quiet", "HEAD"], |_| Ok::<_, &str>(())) {
+                Ok(()) => Diagnostic::ok("repository", toplevel),
+                Err(_) => Diagnostic::warning(
+                    "repository",
+                    format!("{toplevel} has no commit yet, so the hash is a placeholder"),
+                    "make the first commit",
+                ),
+            }
+        }
+        Err(e) if e.to_string().contains("safe.directory") => Diagnostic::error(
+            "repository",
+            "git refuses to use a repository owned by another user",
+            "run `git config --global --add safe.directory <repository>` \
+             if you trust the repository owner",
+        ),
+        Err(e) => Diagnostic::error(
+            "repository",
+            e.to_string(),
+            "run from a cargo workspace in a git repository, \
+             and check FURIOSA_METADATA_GIT_CEILING_DIRECTORIES",
+        ),
+    }
+}
+
+fn check_patterns() -> Diagnostic {
+    match read_policy_patterns() {
+        Ok(patterns) => {
+            // the same pattern with several policies is likely a mistake
+            for (i, a) in patterns.iter().enumerate() {
+                if let Some(b) = patterns[..i].iter().find(|b| b.pattern == a.pattern) {
+                    return Diagnostic::warning(
+                        "patterns",
+                        format!(
+                            "{} appears in both {} and {}, and only {} applies",
+                            a.pattern,
+                            b.policy.var(),
+                            a.policy.var(),
+                            a.policy.max(b.policy).var(),
+                        ),
+                        "remove it from either variable",
+                    );
+                }
+            }
+            for p in &patterns {
+                if let Some(suggestion) = lint_pattern(p.pattern.as_str()) {
+                    return Diagnostic::warning(
+                        "patterns",
+                        format!("{} contains a suspicious pattern {}", p.policy.var(), p.pattern),
+                        suggestion,
+                    );
+                }
+            }
+            Diagnostic::ok("patterns", format!("{} patterns", patterns.len()))
+        }
+        Err(e) => Diagnostic::error(
+            "patterns",
+            e.to_string(),
+            "fix the pattern; see the `glob` crate documentation for the syntax",
+        ),
+    }
+}
+
+/// Checks the environment variables, read with `var`.
+fn check_vars(var: impl Fn(&str) -> Option<String>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for (name, values) in [
+        (OFFLINE_VAR, &["1"][..]),
+        (TIMINGS_VAR, &["1"]),
+        (DETERMINISTIC_VAR, &["1"]),
+        (NO_GIT_RERUN_VAR, &["1"]),
+        (cache::CACHE_VAR, &["1", "warm"]),
+    ] {
+        match var(name) {
+            Some(value) if !values.contains(&value.as_str()) => {
+                diagnostics.push(Diagnostic::warning(
+                    "environment",
+                    format!("{name}={value} is ignored"),
+                    format!("set it to {}", values.join(" or ")),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    // fails the build as well
+    if let Err(e) = parse_clock_skew(var(CLOCK_SKEW_VAR).as_deref()) {
+        diagnostics.push(Diagnostic::error("environment", e, "set it to warn or commit-date"));
+    }
+
+    if let Some(channel) = var(CHANNEL_VAR) {
+        if !is_valid_channel(&channel) {
+            diagnostics.push(Diagnostic::error(
+                "environment",
+                format!("{CHANNEL_VAR}={channel} is not a valid channel"),
+                "use only `a-z`, `0-9` and `-`",
+            ));
+        }
+    }
+
+    if let Some(previous) = var(PREVIOUS_HASH_VAR) {
+        if let Err(e) = parse_git_hash(&previous) {
+            diagnostics.push(Diagnostic::error(
+                "environment",
+                format!("{PREVIOUS_HASH_VAR}: {e}"),
+                "set it to the FURIOSA_GIT_SHORT_HASH of the previous build",
+            ));
+        }
+    }
+
+    if let Some(ceilings) = var(CEILING_VAR) {
+        // git silently ignores them, which is an error in the build script
+        if let Some(ceiling) = ceilings.split(':').find(|ceiling| !Path::new(ceiling).is_absolute())
+        {
+            diagnostics.push(Diagnostic::error(
+                "environment",
+                format!("{CEILING_VAR} contains a non-absolute path {ceiling:?}"),
+                "use absolute paths only",
+            ));
+        }
+    }
+
+    let timestamp_override = var(TIMESTAMP_OVERRIDE_VAR);
+    let source_date_epoch = var("SOURCE_DATE_EPOCH");
+    let deterministic = var(DETERMINISTIC_VAR).as_deref() == Some("1");
+    let resolved = resolve_build_timestamp(
+        timestamp_override.as_deref(),
+        source_date_epoch.as_deref(),
+        deterministic,
+        || Ok(chrono::Utc::now()),
+    );
+    if let Err(e) = resolved {
+        diagnostics.push(Diagnostic::error("environment", e.to_string(), "fix the timestamp"));
+    } else if timestamp_override.is_some() && (source_date_epoch.is_some() || deterministic) {
+        diagnostics.push(Diagnostic::warning(
+            "environment",
+            format!(
+                "{TIMESTAMP_OVERRIDE_VAR} takes precedence over SOURCE_DATE_EPOCH \
+                 and {DETERMINISTIC_VAR}"
+            ),
+            "unset the ones not meant to be used",
+        ));
+    } else if source_date_epoch.is_some() && deterministic {
+        diagnostics.push(Diagnostic::warning(
+            "environment",
+            format!("SOURCE_DATE_EPOCH takes precedence over {DETERMINISTIC_VAR}"),
+            "unset the one not meant to be used",
+        ));
+    }
+
+    if DETERMINISTIC {
+        let placeholder_vars =
+            env::vars().map(|(name, _)| name).filter(|name| name.starts_with(PLACEHOLDER_VAR));
+        for name in
+            [TIMINGS_VAR, BUILDER_SALT_VAR].into_iter().map(str::to_owned).chain(placeholder_vars)
+        {
+            if var(&name).is_some() {
+                diagnostics.push(Diagnostic::warning(
+                    "environment",
+                    format!("{name} is ignored, as the `deterministic` feature is enabled"),
+                    format!("unset {name}"),
+                ));
+            }
+        }
+    }
+
+    if var("FURIOSA_GIT_SHORT_HASH").is_some() {
+        for name in [cache::CACHE_VAR, NO_GIT_RERUN_VAR] {
+            if var(name).is_some() {
+                diagnostics.push(Diagnostic::warning(
+                    "environment",
+                    format!("{name} has no effect, as FURIOSA_GIT_SHORT_HASH is set"),
+                    format!("unset {name}"),
+                ));
+            }
+        }
+    }
+
+    if diagnostics.is_empty() {
+        diagnostics.push(Diagnostic::ok("environment", "no conflicts"));
+    }
+    diagnostics
+}
+
+#[test]
+fn vars() {
+    let check = |vars: &[(&str, &str)]| {
+        let vars: Vec<(String, String)> =
+            vars.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect();
+        check_vars(|name| vars.iter().find(|(n, _)| n == name).map(|(_, value)| value.clone()))
+    };
+
+    assert_eq!(check(&[])[0].severity, Severity::Ok);
+    assert_eq!(check(&[(OFFLINE_VAR, "1"), (cache::CACHE_VAR, "warm")])[0].severity, Severity::Ok);
+
+    let diagnostics = check(&[(OFFLINE_VAR, "true")]);
+    assert_eq!(diagnostics[0].severity, Severity::Warning);
+    assert_eq!(diagnostics[0].message, "FURIOSA_METADATA_OFFLINE=true is ignored");
+
+    let diagnostics = check(&[
+        (TIMESTAMP_OVERRIDE_VAR, "yesterday"),
+        (CHANNEL_VAR, "Nightly"),
+        (PREVIOUS_HASH_VAR, "v1.2.0"),
+    ]);
+    assert_eq!(diagnostics.len(), 3);
+    assert!(diagnostics.iter().all(|d| d.severity == Severity::Error));
+
+    // agrees with the build
+    for value in ["warn", "commit-date", "substitute"] {
+        let diagnostics = check(&[(CLOCK_SKEW_VAR, value)]);
+        let fails = parse_clock_skew(Some(value)).is_err();
+        assert_eq!(diagnostics[0].severity == Severity::Error, fails, "{value}");
+    }
+
+    let diagnostics =
+        check(&[(TIMESTAMP_OVERRIDE_VAR, "2025-01-07T10:00:00Z"), ("SOURCE_DATE_EPOCH", "0")]);
+    assert_eq!(diagnostics[0].severity, Severity::Warning);
+}
+
+#[test]
+fn git_versions() {
+    assert_eq!(parse_git_version("git version 2.39.2"), Some((2, 39)));
+    assert_eq!(parse_git_version("git version 2.45.1.windows.1"), Some((2, 45)));
+    assert_eq!(parse_git_version("hub version 2.14.2"), None);
+}
@@ -0,0 +1,116 @@
+//! Machine-readable reports of failures, so that CI can classify them without parsing
+//! the build script output.
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use crate::json;
+
+const FAILURE_REPORT_FILE_NAME: &str = "furiosa-metadata-failure.json";
+
+/// What kind of failure, in the `kind` of the report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FailureKind {
+    /// git is not installed, and there was no other way to get the hash.
+    GitMissing,
+    /// A command, e.g. git, failed or produced an unexpected output.
+    CommandFailed,
+    /// An updated file matches `FURIOSA_METADATA_FORBID_MODIFIED`.
+    DirtyForbidden,
+    /// `FURIOSA_METADATA_*_MODIFIED` contains an invalid pattern.
+    InvalidPattern,
+    /// Anything else, e.g. an invalid environment variable.
+    Other,
+}
+
+impl FailureKind {
+    fn name(self) -> &'static str {
+        match self {
+            FailureKind::GitMissing => "git-missing",
+            FailureKind::CommandFailed => "command-failed",
+            FailureKind::DirtyForbidden => "dirty-forbidden",
+            FailureKind::InvalidPattern => "invalid-pattern",
+            FailureKind::Other => "other",
+        }
+    }
+}
+
+/// An error that can be classified, and may come from a command.
+#[derive(Debug)]
+pub(crate) struct Failure {
+    kind: FailureKind,
+    message: String,
+    command: Option<String>,
+    stderr: Option<String>,
+}
+
+impl Failure {
+    pub(crate) fn new(kind: FailureKind, message: impl Into<String>) -> Self {
+        Failure { kind, message: message.into(), command: None, stderr: None }
+    }
+
+    /// Records the command line and its stderr, if captured.
+    pub(crate) fn with_command(mut self, command: &str, stderr: Option<&[u8]>) -> Self {
+        self.command = Some(command.to_owned());
+        self.stderr = stderr.map(|stderr| String::from_utf8_lossy(stderr).into_owned());
+        self
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Failure {}
+
+/// Writes the report of `error` to `$OUT_DIR/furiosa-metadata-failure.json`,
+/// or removes the report of the previous failure if succeeded.
+pub(crate) fn write_report(error: Option<&(dyn Error + 'static)>) -> io::Result<()> {
+    let Some(out_dir) = env::var_os("OUT_DIR") else {
+        return Ok(());
+    };
+    let path = Path::new(&out_dir).join(FAILURE_REPORT_FILE_NAME);
+    match error {
+        Some(error) => fs::write(path, report(error).to_string()),
+        None => match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
+}
+
+fn report(error: &(dyn Error + 'static)) -> json::Value {
+    let failure = error.downcast_ref::<Failure>();
+    json::Value::Object(vec![
+        ("kind".to_owned(), failure.map_or(FailureKind::Other, |f| f.kind).name().into()),
+        ("message".to_owned(), error.to_string().into()),
+        ("command".to_owned(), failure.and_then(|f| f.command.as_deref()).into()),
+        ("stderr".to_owned(), failure.and_then(|f| f.stderr.as_deref()).into()),
+    ])
+}
+
+#[test]
+fn reports() {
+    let error: Box<dyn Error> = Failure::new(FailureKind::CommandFailed, "`git status` failed")
+        .with_command("git status", Some(b"fatal: not a git repository\n"))
+        .into();
+    let value = report(&*error);
+    assert_eq!(value.get("kind").and_then(json::Value::as_str), Some("command-failed"));
+    assert_eq!(value.get("command").and_then(json::Value::as_str), Some("git status"));
+    assert_eq!(
+        value.get("stderr").and_then(json::Value::as_str),
+        Some("fatal: not a git repository\n")
+    );
+
+    let error: Box<dyn Error> = "Invalid prefix".into();
+    let value = report(&*error);
+    assert_eq!(value.get("kind").and_then(json::Value::as_str), Some("other"));
+    assert_eq!(value.get("message").and_then(json::Value::as_str), Some("Invalid prefix"));
+    assert_eq!(value.get("command"), Some(&json::Value::Null));
+}
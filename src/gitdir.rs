@@ -0,0 +1,216 @@
+//! Reads a git repository directly, for when git itself is not available.
+//!
+//! Only the loose and packed refs are supported, which is enough to resolve HEAD.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::hash;
+
+/// The directories of a git repository, which differ for a linked worktree.
+pub(crate) struct GitDir {
+    /// The per-worktree directory with `HEAD` and `index`, e.g. `.git/worktrees/<name>`.
+    pub(crate) git_dir: PathBuf,
+    /// The directory shared by all worktrees, with most refs and objects.
+    pub(crate) common_dir: PathBuf,
+}
+
+impl GitDir {
+    /// Finds the repository containing `dir` in the same way as git,
+    /// without looking into `ceilings` or their parents.
+    pub(crate) fn discover(
+        dir: &Path,
+        ceilings: &[&Path],
+    ) -> Result<Option<GitDir>, Box<dyn std::error::Error>> {
+        for dir in dir.ancestors() {
+            let dot_git = dir.join(".git");
+            if dot_git.exists() {
+                return GitDir::open(&dot_git).map(Some);
+            }
+            if ceilings.contains(&dir.parent().unwrap_or(dir)) {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Opens `.git`, which is either the repository itself or a file pointing at it
+    /// (`gitdir: <path>`), as in linked worktrees and submodules.
+    pub(crate) fn open(dot_git: &Path) -> Result<GitDir, Box<dyn std::error::Error>> {
+        let git_dir = if dot_git.is_file() {
+            let contents = fs::read_to_string(dot_git)
+                .map_err(|e| format!("Failed to read {}: {e}", dot_git.display()))?;
+            let path = contents
+                .trim_end()
+                .strip_prefix("gitdir: ")
+                .ok_or_else(|| format!("Unexpected contents in {}", dot_git.display()))?;
+            dot_git.parent().unwrap_or(dot_git).join(path)
+        } else {
+            dot_git.to_owned()
+        };
+
+        // linked worktrees point back at the main repository
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(path) => git_dir.join(path.trim_end()),
+            Err(e) if e.kind() == ErrorKind::NotFound => git_dir.clone(),
+            Err(e) => {
+                return Err(format!("Failed to read {}/commondir: {e}", git_dir.display()).into())
+            }
+        };
+
+        if common_dir.join("reftable").is_dir() {
+            return Err(
+                format!("{} uses reftable, which is not supported", git_dir.display()).into()
+            );
+        }
+        Ok(GitDir { git_dir, common_dir })
+    }
+
+    /// Returns the commit id of HEAD, or `None` if HEAD has no commit yet.
+    pub(crate) fn head_commit(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let mut name = "HEAD".to_owned();
+        // git also gives up after 5 levels of symbolic refs
+        for _ in 0..5 {
+            let path = self.ref_path(&name);
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == ErrorKind::NotFound => return self.packed_ref(&name),
+                Err(e) => return Err(format!("Failed to read {}: {e}", path.display()).into()),
+            };
+
+            let contents = contents.trim_end();
+            match contents.strip_prefix("ref: ") {
+                Some(target) => name = target.to_owned(),
+                None if hash::is_full_hash(contents) => return Ok(Some(contents.to_owned())),
+                None => return Err(format!("Unexpected contents in {}", path.display()).into()),
+            }
+        }
+        Err(format!("Too deeply nested symbolic ref {name}").into())
+    }
+
+    /// Returns the existing files and directories that change when HEAD moves to another commit
+    /// or the index is updated, for `cargo:rerun-if-changed`.
+    pub(crate) fn watched_paths(&self) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let mut paths = vec![self.git_dir.join("index")];
+        let mut name = "HEAD".to_owned();
+        for _ in 0..5 {
+            let path = self.ref_path(&name);
+            match fs::read_to_string(&path) {
+                Ok(contents) => {
+                    paths.push(path);
+                    match contents.trim_end().strip_prefix("ref: ") {
+                        Some(target) => name = target.to_owned(),
+                        None => break,
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    // either packed or not created yet, so also watch where it will be created
+                    paths.push(self.common_dir.join("packed-refs"));
+                    paths.extend(
+                        path.ancestors().skip(1).find(|dir| dir.is_dir()).map(Path::to_owned),
+                    );
+                    break;
+                }
+                Err(e) => return Err(format!("Failed to read {}: {e}", path.display()).into()),
+            }
+        }
+
+        // cargo always reruns the build script for a missing path
+        paths.retain(|path| path.exists());
+        Ok(paths)
+    }
+
+    /// Returns the path of a loose ref, which is per-worktree for HEAD and a few special refs.
+    fn ref_path(&self, name: &str) -> PathBuf {
+        let per_worktree = !name.starts_with("refs/")
+            || ["refs/bisect/", "refs/worktree/", "refs/rewritten/"]
+                .iter()
+                .any(|prefix| name.starts_with(prefix));
+        if per_worktree {
+            self.git_dir.join(name)
+        } else {
+            self.common_dir.join(name)
+        }
+    }
+
+    /// Looks up a ref in `packed-refs`, consisting of `<commit id> <name>` lines.
+    fn packed_ref(&self, name: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let path = self.common_dir.join("packed-refs");
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to read {}: {e}", path.display()).into()),
+        };
+        Ok(parse_packed_refs(&contents, name).map(str::to_owned))
+    }
+}
+
+fn parse_packed_refs<'a>(contents: &'a str, name: &str) -> Option<&'a str> {
+    contents
+        .lines()
+        // skip the header (`# pack-refs with: ...`) and peeled tags (`^<commit id>`)
+        .filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+        .filter_map(|line| line.split_once(' '))
+        .find(|&(_, ref_name)| ref_name == name)
+        .map(|(id, _)| id)
+}
+
+#[test]
+fn packed_refs() {
+    let contents = "# pack-refs with: peeled fully-peeled sorted \n\
+                    0123456789abcdef0123456789abcdef01234567 refs/heads/main\n\
+                    89abcdef0123456789abcdef0123456789abcdef refs/tags/v1.0.0\n\
+                    ^fedcba9876543210fedcba9876543210fedcba98\n";
+    assert_eq!(
+        parse_packed_refs(contents, "refs/heads/main"),
+        Some("0123456789abcdef0123456789abcdef01234567")
+    );
+    assert_eq!(
+        parse_packed_refs(contents, "refs/tags/v1.0.0"),
+        Some("89abcdef0123456789abcdef0123456789abcdef")
+    );
+    assert_eq!(parse_packed_refs(contents, "refs/heads/other"), None);
+}
+
+#[test]
+fn worktree() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("furiosa-metadata-gitdir-{}", std::process::id()));
+    let main = dir.join("main/.git");
+    let worktree = main.join("worktrees/wt");
+    fs::create_dir_all(main.join("refs/heads"))?;
+    fs::create_dir_all(&worktree)?;
+    fs::create_dir_all(dir.join("wt/src"))?;
+    fs::write(main.join("HEAD"), "ref: refs/heads/main\n")?;
+    fs::write(main.join("refs/heads/main"), "0123456789abcdef0123456789abcdef01234567\n")?;
+    fs::write(
+        main.join("packed-refs"),
+        "89abcdef0123456789abcdef0123456789abcdef refs/heads/wt\n",
+    )?;
+    fs::write(worktree.join("HEAD"), "ref: refs/heads/wt\n")?;
+    fs::write(worktree.join("commondir"), "../..\n")?;
+    fs::write(dir.join("wt/.git"), format!("gitdir: {}\n", worktree.display()))?;
+
+    let main_dir = GitDir::discover(&dir.join("main"), &[])?.ok_or("no repository")?;
+    let main_head = main_dir.head_commit()?;
+    let main_paths = main_dir.watched_paths()?;
+    let wt_dir = GitDir::discover(&dir.join("wt/src"), &[])?.ok_or("no repository")?;
+    let wt_head = wt_dir.head_commit()?;
+    let wt_paths = wt_dir.watched_paths()?;
+    let outside = GitDir::discover(&dir.join("wt/src"), &[&dir.join("wt")])?;
+    fs::remove_dir_all(&dir)?;
+
+    assert_eq!(main_head.as_deref(), Some("0123456789abcdef0123456789abcdef01234567"));
+    assert_eq!(main_paths, [main.join("HEAD"), main.join("refs/heads/main")]);
+    assert_eq!(wt_head.as_deref(), Some("89abcdef0123456789abcdef0123456789abcdef"));
+    assert_eq!(
+        wt_paths,
+        [
+            worktree.join("HEAD"),
+            worktree.join("../../packed-refs"),
+            worktree.join("../../refs/heads")
+        ]
+    );
+    assert!(outside.is_none());
+    Ok(())
+}
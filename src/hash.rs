@@ -0,0 +1,61 @@
+//! Helpers for tools parsing the Git hashes stamped by this crate, e.g. `0123456789-modified`.
+//!
+//! Placeholders (see [`set_metadata_env_vars`](crate::set_metadata_env_vars)) are not hashes,
+//! so they are rejected by the strict functions here.
+
+/// The suffix put to the short hash if the repository is dirty.
+pub const DIRTY_SUFFIX: &str = "-modified";
+
+/// Splits `hash` into the hash itself and whether it has the [`DIRTY_SUFFIX`].
+///
+/// This doesn't validate the hash; see [`parse_git_hash`] for that.
+pub fn split_dirty_suffix(hash: &str) -> (&str, bool) {
+    match hash.strip_suffix(DIRTY_SUFFIX) {
+        Some(hash) => (hash, true),
+        None => (hash, false),
+    }
+}
+
+/// Returns true if `s` is a full commit id, i.e. 40 (SHA-1) or 64 (SHA-256) lowercase hex digits.
+pub fn is_full_hash(s: &str) -> bool {
+    matches!(s.len(), 40 | 64) && is_lower_hex(s)
+}
+
+/// Returns true if `s` is an abbreviated or full commit id, i.e. 7 to 64 lowercase hex digits.
+///
+/// Git never abbreviates to less than 7 digits, and this crate uses at least 9.
+pub fn is_short_hash(s: &str) -> bool {
+    (7..=64).contains(&s.len()) && is_lower_hex(s)
+}
+
+/// Parses a hash stamped by this crate into the commit id and whether the repository was dirty,
+/// e.g. `("0123456789", true)` for `0123456789-modified`.
+pub fn parse_git_hash(s: &str) -> Result<(&str, bool), Box<dyn std::error::Error>> {
+    let (hash, dirty) = split_dirty_suffix(s);
+    if !is_short_hash(hash) {
+        return Err(format!("Not a Git hash: {s:?}").into());
+    }
+    Ok((hash, dirty))
+}
+
+fn is_lower_hex(s: &str) -> bool {
+    s.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[test]
+fn hashes() -> Result<(), Box<dyn std::error::Error>> {
+    assert_eq!(split_dirty_suffix("0123456789-modified"), ("0123456789", true));
+    assert_eq!(split_dirty_suffix("unknown"), ("unknown", false));
+
+    assert_eq!(parse_git_hash("0123456789-modified")?, ("0123456789", true));
+    assert_eq!(parse_git_hash("abcdef0")?, ("abcdef0", false));
+    for bad in ["", "unknown", "abcdef", "ABCDEF0123", "0123456789-dirty", "-modified"] {
+        assert!(parse_git_hash(bad).is_err(), "{bad:?}");
+    }
+
+    assert!(is_full_hash("0123456789abcdef0123456789abcdef01234567"));
+    assert!(!is_full_hash("0123456789"));
+    assert!(is_short_hash("0123456789"));
+    assert!(!is_short_hash(&"0".repeat(65)));
+    Ok(())
+}
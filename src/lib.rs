@@ -1,13 +1,42 @@
 #![warn(rust_2018_idioms)]
 
+use std::collections::BTreeMap;
 use std::env::{self, VarError};
 use std::fmt::Display;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str;
 
-use chrono::offset::Utc;
-use glob::Pattern;
+use chrono::offset::{TimeZone, Utc};
+use chrono::DateTime;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use crate::cache::GitCache;
+use crate::failure::{Failure, FailureKind};
+use crate::gitdir::GitDir;
+use crate::patterns::{Policy, PolicyPattern};
+
+mod cache;
+mod datahash;
+pub mod debuginfo;
+pub mod diff;
+pub mod doctor;
+mod failure;
+mod gitdir;
+pub mod hash;
+mod json;
+pub mod list;
+mod metadata;
+mod patterns;
+pub mod release;
+mod summary;
+pub mod tuning;
+
+pub use crate::metadata::{
+    BuildMetadata, Compatibility, Peer, PeerMetadata, PublicMetadata, Visibility,
+};
 
 /// Generates the build metadata constants.
 ///
@@ -16,7 +45,7 @@ use glob::Pattern;
 ///
 /// * `VERSION`
 /// * `GIT_SHORT_HASH`
-/// * `BUILD_TIMESTAMP`
+/// * `BUILD_TIMESTAMP` (e.g. `2025-01-07T10:00:00Z`)
 #[macro_export]
 macro_rules! metadata_constants {
     () => {
@@ -26,60 +55,1655 @@ macro_rules! metadata_constants {
     };
 }
 
-/// Sets the build metadata environment variables.
-///
-/// This is designed to be used as a part of a Cargo build script and sets the following
-/// environment variables:
+/// Generates the additional build metadata constants, in addition to [`metadata_constants!`]:
+///
+/// * `BUILD_TIMESTAMP_BASIC` (ISO 8601 basic format, e.g. `20250107T100000Z`)
+/// * `BUILD_TIMESTAMP_FILENAME` (safe for file names, e.g. `2025-01-07T10-00-00Z`)
+/// * `BUILD_CACHE` (the active compilation caches, e.g. `rustc-wrapper=sccache;incremental=unset`)
+/// * `CHANNEL` (the release channel, e.g. `nightly`)
+/// * `METADATA_SOURCE` (where the fields came from, e.g. `hash=git;dirty=git;timestamp=clock`)
+///
+/// These are separately opted in, so that they don't collide with existing constants.
+#[macro_export]
+macro_rules! extra_metadata_constants {
+    () => {
+        pub const BUILD_TIMESTAMP_BASIC: &str = env!("FURIOSA_BUILD_TIMESTAMP_BASIC");
+        pub const BUILD_TIMESTAMP_FILENAME: &str = env!("FURIOSA_BUILD_TIMESTAMP_FILENAME");
+        pub const BUILD_CACHE: &str = env!("FURIOSA_BUILD_CACHE");
+        pub const CHANNEL: &str = env!("FURIOSA_CHANNEL");
+        pub const METADATA_SOURCE: &str = env!("FURIOSA_METADATA_SOURCE");
+    };
+}
+
+/// Generates the detailed git metadata constants, in addition to [`metadata_constants!`]:
+///
+/// * `GIT_HASH`
+/// * `GIT_BRANCH` (a placeholder if HEAD is detached)
+/// * `GIT_DESCRIBE` (as in `git describe --tags --always`)
+/// * `GIT_COMMIT_DATE` (e.g. `2025-01-07T10:00:00Z`)
+/// * `GIT_DIRTY` (an `Option<bool>`, which is `None` if not known)
+///
+/// These are separately opted in, so that they don't collide with existing constants.
+#[macro_export]
+macro_rules! git_metadata_constants {
+    () => {
+        pub const GIT_HASH: &str = env!("FURIOSA_GIT_HASH");
+        pub const GIT_BRANCH: &str = env!("FURIOSA_GIT_BRANCH");
+        pub const GIT_DESCRIBE: &str = env!("FURIOSA_GIT_DESCRIBE");
+        pub const GIT_COMMIT_DATE: &str = env!("FURIOSA_GIT_COMMIT_DATE");
+        pub const GIT_DIRTY: Option<bool> = $crate::__parse_bool(env!("FURIOSA_GIT_DIRTY"));
+    };
+}
+
+/// Generates the build configuration constants, in addition to [`metadata_constants!`]:
+///
+/// * `BUILD_FEATURES` (enabled cargo features as seen by build scripts, e.g. `default;foo_bar`,
+///   encoded as described in [`list`])
+/// * `BUILD_PROFILE` (`debug` or `release`)
+/// * `BUILD_TARGET` (e.g. `x86_64-unknown-linux-gnu`)
+/// * `RUSTC_VERSION` (e.g. `rustc 1.80.1 (3f5fd8dd4 2024-08-06)`)
+/// * `BUILD_CONFIG_FINGERPRINT` (a short hash of all above, for a quick comparison,
+///   e.g. `sha256:0123456789abcdef`; see [`HashAlgorithm`])
+///
+/// These are placeholders unless [`MetadataOptions::include_build_config`] is set.
+#[macro_export]
+macro_rules! build_config_constants {
+    () => {
+        pub const BUILD_FEATURES: &str = env!("FURIOSA_BUILD_FEATURES");
+        pub const BUILD_PROFILE: &str = env!("FURIOSA_BUILD_PROFILE");
+        pub const BUILD_TARGET: &str = env!("FURIOSA_BUILD_TARGET");
+        pub const RUSTC_VERSION: &str = env!("FURIOSA_RUSTC_VERSION");
+        pub const BUILD_CONFIG_FINGERPRINT: &str = env!("FURIOSA_BUILD_CONFIG_FINGERPRINT");
+    };
+}
+
+/// Assigns the symbol version of [`MetadataOptions::symbol_version`] to given `#[no_mangle]`
+/// symbols of a `cdylib`, so that e.g. `objdump -T` shows them as `FURIOSA_RT_1.2.3`.
+///
+/// This should be invoked once in the `cdylib` crate:
+///
+/// ```ignore
+/// furiosa_metadata::symbol_versions!(furiosa_rt_init, furiosa_rt_run);
+/// ```
+///
+/// It does nothing unless the build script set `cfg(furiosa_symbol_versions)`, i.e. for targets
+/// other than Linux, for packages without a `cdylib`, or without the option, so that the symbols
+/// of test binaries and `rlib`s are left alone.
+#[macro_export]
+macro_rules! symbol_versions {
+    ($($symbol:ident),+ $(,)?) => {
+        $(
+            #[cfg(furiosa_symbol_versions)]
+            ::core::arch::global_asm!(concat!(
+                ".symver ",
+                stringify!($symbol),
+                ", ",
+                stringify!($symbol),
+                "@@",
+                env!("FURIOSA_SYMBOL_VERSION"),
+            ));
+        )+
+    };
+}
+
+/// Fails to compile unless built from a branch matching `pattern`, e.g.
+/// `assert_built_from_branch!("release/*")`, where `*` matches any characters (including `/`)
+/// and `?` matches any single character.
+///
+/// Note that a detached HEAD, as often checked out in CI, has no branch and always fails.
+#[macro_export]
+macro_rules! assert_built_from_branch {
+    ($pattern:literal) => {
+        const _: () = assert!(
+            $crate::__matches($pattern, env!("FURIOSA_GIT_BRANCH")),
+            concat!("Not built from a branch matching ", $pattern),
+        );
+    };
+}
+
+/// Fails to compile unless built by rustc of given version or later,
+/// e.g. `assert_min_rustc!("1.75")`.
+///
+/// This requires [`MetadataOptions::include_build_config`], and always fails otherwise.
+#[macro_export]
+macro_rules! assert_min_rustc {
+    ($version:literal) => {
+        const _: () = assert!(
+            $crate::__rustc_at_least(env!("FURIOSA_RUSTC_VERSION"), $version),
+            concat!(
+                "Not built by rustc ",
+                $version,
+                " or later, or `MetadataOptions::include_build_config` is not set",
+            ),
+        );
+    };
+}
+
+#[doc(hidden)]
+pub const fn __parse_bool(s: &str) -> Option<bool> {
+    match s.as_bytes() {
+        b"true" => Some(true),
+        b"false" => Some(false),
+        _ => None,
+    }
+}
+
+#[doc(hidden)]
+pub const fn __matches(pattern: &str, s: &str) -> bool {
+    let (p, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut pi, mut si) = (0, 0);
+    // the last `*` and where it started matching, to backtrack to
+    let (mut star, mut mark) = (None, 0);
+    while si < s.len() {
+        if pi < p.len() && (p[pi] == b'?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < p.len() && p[pi] == b'*' {
+            star = Some(pi);
+            mark = si;
+            pi += 1;
+        } else if let Some(star) = star {
+            pi = star + 1;
+            mark += 1;
+            si = mark;
+        } else {
+            return false;
+        }
+    }
+    while pi < p.len() && p[pi] == b'*' {
+        pi += 1;
+    }
+    pi == p.len()
+}
+
+#[doc(hidden)]
+pub const fn __rustc_at_least(rustc_version: &str, min: &str) -> bool {
+    /// Parses up to 3 dot-separated numbers from `start`, stopping at any other character.
+    const fn parse(s: &[u8], start: usize) -> [u32; 3] {
+        let mut parts = [0; 3];
+        let (mut i, mut part) = (start, 0);
+        while i < s.len() && part < parts.len() {
+            match s[i] {
+                c @ b'0'..=b'9' => parts[part] = parts[part] * 10 + (c - b'0') as u32,
+                b'.' => part += 1,
+                _ => break,
+            }
+            i += 1;
+        }
+        parts
+    }
+
+    let (version, prefix) = (rustc_version.as_bytes(), b"rustc ");
+    let mut i = 0;
+    while i < prefix.len() {
+        // e.g. a placeholder without `MetadataOptions::include_build_config`
+        if i >= version.len() || version[i] != prefix[i] {
+            return false;
+        }
+        i += 1;
+    }
+    let (version, min) = (parse(version, prefix.len()), parse(min.as_bytes(), 0));
+    i = 0;
+    while i < version.len() {
+        if version[i] != min[i] {
+            return version[i] > min[i];
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Sets the build metadata environment variables.
+///
+/// This is designed to be used as a part of a Cargo build script and sets the following
+/// environment variables:
+///
+/// * `FURIOSA_GIT_SHORT_HASH`
+/// * `FURIOSA_GIT_HASH`
+/// * `FURIOSA_GIT_BRANCH`
+/// * `FURIOSA_GIT_DESCRIBE`
+/// * `FURIOSA_GIT_COMMIT_DATE`
+/// * `FURIOSA_GIT_DIRTY` (`true` or `false`)
+/// * `FURIOSA_BUILD_TIMESTAMP`
+/// * `FURIOSA_BUILD_TIMESTAMP_BASIC`
+/// * `FURIOSA_BUILD_TIMESTAMP_FILENAME`
+/// * `FURIOSA_BUILD_CACHE`
+/// * `FURIOSA_CHANNEL`
+/// * `FURIOSA_METADATA_SOURCE`, which records where each field came from, e.g.
+///   `hash=env;dirty=unknown;timestamp=clock` for an injected `FURIOSA_GIT_SHORT_HASH`.
+///
+/// It also writes a release manifest fragment to `$OUT_DIR/release-manifest.json`
+/// for the release automation (see [`MetadataOptions::release_manifest`]).
+/// When it fails, `$OUT_DIR/furiosa-metadata-failure.json` describes the failure for CI, with
+/// `message`, `command` and `stderr` of the failed command if any, and `kind`, which is one of
+/// `git-missing`, `command-failed`, `dirty-forbidden`, `invalid-pattern` and `other`.
+///
+/// All variables set for each crate in the workspace are also collected into
+/// `<target dir>/furiosa-metadata/summary.json`, to be archived with the build. It lists
+/// the latest records of the crates in the profile directory of the last build (e.g.
+/// `target/release`), and failing to write it only results in a warning.
+///
+/// Following environment variables may be used for configuration:
+///
+/// * `FURIOSA_METADATA_EXPECT_MODIFIED` is a colon-separated list of glob patterns
+///   that are ignored for the dirty repository detection (puts `-modified` to the hash).
+///   Patterns match the full path, where `*` also matches `/`, so `*.bak` matches `foo/bar.bak`.
+///   See the `glob` crate documentation for the full pattern syntax.
+/// * `FURIOSA_METADATA_IGNORE_MODIFIED` is the same, but the matching paths are silently ignored.
+/// * `FURIOSA_METADATA_WARN_MODIFIED` is the same, but the matching paths are reported as warnings.
+/// * `FURIOSA_METADATA_FORBID_MODIFIED` is a list of patterns that must never be updated;
+///   any matching path fails the build. This takes precedence over all other patterns, followed by
+///   `FURIOSA_METADATA_WARN_MODIFIED`, `FURIOSA_METADATA_EXPECT_MODIFIED` and
+///   `FURIOSA_METADATA_IGNORE_MODIFIED`.
+///   When the crate is built from a package (e.g. `cargo package` verification, or vendored
+///   sources), the hash and its `-modified` suffix are read from its `.cargo_vcs_info.json`
+///   instead, as an enclosing repository (if any) is not the one the package was created from.
+/// * `FURIOSA_METADATA_OFFLINE`, when set to `1`, guarantees that no operation touches the network.
+///   Git is run with all transports disabled, so a partial clone that would need to lazily fetch
+///   missing objects fails instead, and options that run arbitrary commands are rejected.
+/// * `FURIOSA_METADATA_CHANNEL` overrides the release channel, which consists of `a-z`, `0-9`
+///   and `-`. By default it is `release` for versions without a pre-release part, and otherwise
+///   the leading alphabetic part of the pre-release (`nightly` for `1.2.0-nightly.20250107`),
+///   or `prerelease` if there is none. The channel is also available as
+///   `cfg(furiosa_channel = "...")` to the crate being built.
+/// * `FURIOSA_METADATA_GIT_CEILING_DIRECTORIES` is a colon-separated list of absolute paths that
+///   bounds the repository discovery, in addition to the standard `GIT_CEILING_DIRECTORIES`.
+///   Git doesn't look for a repository in any of them or their parents, so a scratch workspace
+///   nested in an unrelated repository fails to build instead of using the outer repository's hash.
+///
+/// The build timestamp is determined in the following order, for the reproducible builds:
+///
+/// 1. `FURIOSA_BUILD_TIMESTAMP_OVERRIDE`, in the ISO 8601 extended (RFC 3339) or basic format
+///    with a time zone (e.g. `2025-01-07T10:00:00Z` or `20250107T100000Z`), so that a build farm
+///    can stamp all artifacts of a release with the same timestamp.
+/// 2. [`SOURCE_DATE_EPOCH`](https://reproducible-builds.org/specs/source-date-epoch/),
+///    in seconds since the Unix epoch.
+/// 3. The committer date of HEAD, if `FURIOSA_METADATA_DETERMINISTIC` is set to `1`.
+///    This fails if the hash is not from git (e.g. injected, or read from `.cargo_vcs_info.json`),
+///    as the date is not known, so set `SOURCE_DATE_EPOCH` in that case.
+/// 4. The current time.
+///
+/// The current time is checked against the committer date of HEAD, as containers with a
+/// misconfigured clock are known to build "in 1970". A clock more than a day behind the commit
+/// date, or more than 10 years ahead of it, results in a warning, or the committer date is used
+/// instead if `FURIOSA_METADATA_CLOCK_SKEW` is set to `commit-date` (`warn` by default).
+///
+/// When `FURIOSA_METADATA_TIMINGS` is set to `1`, `FURIOSA_BUILD_INVOCATION_ID` is set to
+/// an identifier of the current cargo invocation, and the variables are also recorded
+/// into `<target dir>/cargo-timings/furiosa-metadata-<id>/<package>.json` next to the reports of
+/// `cargo build --timings`. Note that a build script is not rerun for every invocation, so the
+/// identifier is the one of the invocation that last ran the build script.
+///
+/// The build script is rerun when HEAD moves to another commit or the index is updated, unless
+/// `FURIOSA_METADATA_NO_GIT_RERUN` is set to `1`. Modifications not yet added to the index don't
+/// trigger a rerun by themselves, as watching the whole working tree would rerun it on every build.
+///
+/// When `FURIOSA_METADATA_CACHE` is set to `1`, the git metadata is computed once per repository
+/// in a cargo invocation and shared by all build scripts using this crate, under
+/// `<target dir>/furiosa-metadata`. Only the first one records `$OUT_DIR/pattern-trace.json`.
+/// The invocation is identified by the cargo process on Unix, and by the jobserver of cargo
+/// (`CARGO_MAKEFLAGS`) elsewhere, e.g. on Windows; if neither is known the cache is not used.
+/// When set to `warm`, it is also reused by later invocations until HEAD, its ref, the index or
+/// the `FURIOSA_METADATA_*_MODIFIED` patterns change, skipping `git status` in an unchanged tree.
+/// This trades accuracy for speed, as modifications not yet added to the index (including new
+/// untracked files) are not noticed until then, and warnings for updated paths are not repeated.
+/// If git is not installed at all, HEAD is read from the repository directly,
+/// but the repository is not checked for modifications.
+///
+/// When `FURIOSA_METADATA_BUILDER_SALT` is set, `FURIOSA_BUILDER_FINGERPRINT` is set to a salted
+/// hash of the host name and the user name, so that builds from the same machine can be correlated
+/// without embedding either of them. Keep the salt secret, or the names may be guessed back.
+///
+/// With [`MetadataOptions::check_release_tags`], a release tag at HEAD (see
+/// [`release::parse_release_tag`]) disagreeing with the version of the package results in
+/// a warning. `furiosa-metadata install-hooks` installs a pre-push hook which rejects such tags
+/// before they are pushed.
+///
+/// When `FURIOSA_METADATA_PREVIOUS_HASH` is set to the hash of the previously deployed build
+/// (e.g. `0123456789`), `FURIOSA_CHANGED_COMPONENTS` lists the packages and other top-level paths
+/// changed since then (see [`release::changed_components`]), encoded as described in [`list`],
+/// so that an updater can show what changed. It is a placeholder when built from a package.
+///
+/// The `deterministic` feature makes the build reproducible in one switch, as if
+/// `FURIOSA_METADATA_DETERMINISTIC` were set to `1`, and also ignores `FURIOSA_METADATA_TIMINGS`,
+/// `FURIOSA_METADATA_BUILDER_SALT` and the placeholder variables below, which always use `unknown`.
+///
+/// When a value is not available, e.g. the hash in a repository without any commit,
+/// a placeholder is used instead. It is `unknown` by default, but can be configured with
+/// `FURIOSA_METADATA_PLACEHOLDER` for all fields, or `FURIOSA_METADATA_PLACEHOLDER_<FIELD>`
+/// for each field (e.g. `FURIOSA_METADATA_PLACEHOLDER_GIT_SHORT_HASH=0000000`).
+/// A build outside a git checkout fails by default, but may fall back to a file or a placeholder
+/// (see [`MetadataOptions::fallback_hash_file`] and [`MetadataOptions::allow_missing_git`]).
+pub fn set_metadata_env_vars() -> Result<(), Box<dyn std::error::Error>> {
+    set_metadata_env_vars_with(&MetadataOptions::default())
+}
+
+/// Same as [`set_metadata_env_vars`], but with additional options.
+pub fn set_metadata_env_vars_with(
+    options: &MetadataOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let result = emit_metadata_env_vars(options);
+    if let Err(e) = failure::write_report(result.as_ref().err().map(|e| &**e)) {
+        eprintln!("[furiosa-metadata] Failed to write the failure report: {e}");
+    }
+    result
+}
+
+fn emit_metadata_env_vars(options: &MetadataOptions) -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-env-changed={OFFLINE_VAR}");
+    println!("cargo:rerun-if-env-changed=GIT_CEILING_DIRECTORIES");
+    println!("cargo:rerun-if-env-changed={CEILING_VAR}");
+    if is_offline() && !options.command_fields.is_empty() {
+        return Err(format!(
+            "{OFFLINE_VAR} is set, but command fields may access the network and are not allowed"
+        )
+        .into());
+    }
+
+    if options.max_value_len < TRUNCATION_MARKER.len() {
+        return Err(format!("Too small maximum value length {}", options.max_value_len).into());
+    }
+    if let Some(prefix) = options.prefixes.iter().find(|prefix| !is_valid_name(prefix)) {
+        return Err(format!("Invalid prefix {prefix:?}").into());
+    }
+
+    emit_git_rerun_if_changed()?;
+
+    let mut vars = Vec::new();
+
+    let (hash, details) = resolve_git_metadata(options)?;
+    vars.push(("FURIOSA_GIT_SHORT_HASH".to_owned(), hash.short_hash));
+
+    let detail_var =
+        |field: &str, value: Option<String>| -> Result<_, Box<dyn std::error::Error>> {
+            Ok((format!("FURIOSA_{field}"), value.map_or_else(|| placeholder(field), Ok)?))
+        };
+    vars.push(detail_var("GIT_HASH", details.as_ref().map(|d| d.hash.clone()).or(hash.full_hash))?);
+    vars.push(detail_var("GIT_BRANCH", details.as_ref().and_then(|d| d.branch.clone()))?);
+    vars.push(detail_var("GIT_DESCRIBE", details.as_ref().map(|d| d.describe.clone()))?);
+    vars.push(detail_var(
+        "GIT_COMMIT_DATE",
+        details.as_ref().map(|d| format_timestamp(&d.commit_time)),
+    )?);
+    vars.push(detail_var("GIT_DIRTY", hash.dirty.map(|dirty| dirty.to_string()))?);
+
+    if options.check_release_tags && details.is_some() && !is_packaged() {
+        // only a warning, which must not fail the build either
+        if let Err(e) = check_head_tags() {
+            println!("cargo:warning=Failed to check the release tags at HEAD: {e}");
+        }
+    }
+
+    let (build_timestamp, timestamp_source) = build_timestamp(details.as_ref())?;
+
+    let sources =
+        [("hash", hash.hash_source), ("dirty", hash.dirty_source), ("timestamp", timestamp_source)];
+    let sources: Vec<String> =
+        sources.iter().map(|(field, source)| format!("{field}={}", source.name())).collect();
+    vars.push(("FURIOSA_METADATA_SOURCE".to_owned(), sources.join(";")));
+
+    vars.push(("FURIOSA_BUILD_TIMESTAMP".to_owned(), format_timestamp(&build_timestamp)));
+    vars.push((
+        "FURIOSA_BUILD_TIMESTAMP_BASIC".to_owned(),
+        build_timestamp.format("%Y%m%dT%H%M%SZ").to_string(),
+    ));
+    vars.push((
+        "FURIOSA_BUILD_TIMESTAMP_FILENAME".to_owned(),
+        build_timestamp.format("%Y-%m-%dT%H-%M-%SZ").to_string(),
+    ));
+    vars.push(("FURIOSA_BUILD_CACHE".to_owned(), build_cache()));
+    let channel = release_channel()?;
+    emit_channel_cfg(&channel);
+    vars.push(("FURIOSA_CHANNEL".to_owned(), channel));
+
+    println!("cargo:rerun-if-env-changed={TIMINGS_VAR}");
+    let invocation_id = match env::var(TIMINGS_VAR).as_deref() {
+        Ok("1") if DETERMINISTIC => {
+            eprintln!("[furiosa-metadata] {TIMINGS_VAR} is ignored in the deterministic mode.");
+            None
+        }
+        Ok("1") => Some(invocation_id().ok_or("cargo invocation id is not available")?),
+        _ => None,
+    };
+    let invocation_id_var = match &invocation_id {
+        Some(id) => id.clone(),
+        None => placeholder("BUILD_INVOCATION_ID")?,
+    };
+    vars.push(("FURIOSA_BUILD_INVOCATION_ID".to_owned(), invocation_id_var));
+
+    println!("cargo:rerun-if-env-changed={BUILDER_SALT_VAR}");
+    let fingerprint = match non_empty_var(BUILDER_SALT_VAR)? {
+        Some(_) if DETERMINISTIC => {
+            eprintln!(
+                "[furiosa-metadata] {BUILDER_SALT_VAR} is ignored in the deterministic mode."
+            );
+            placeholder("BUILDER_FINGERPRINT")?
+        }
+        Some(salt) => builder_fingerprint(options.hash_algorithm, &salt, &builder_identity()?),
+        None => placeholder("BUILDER_FINGERPRINT")?,
+    };
+    vars.push(("FURIOSA_BUILDER_FINGERPRINT".to_owned(), fingerprint));
+
+    let build_config = if options.include_build_config {
+        build_config(options.hash_algorithm)?
+    } else {
+        BUILD_CONFIG_FIELDS.iter().map(|field| placeholder(field)).collect::<Result<_, _>>()?
+    };
+    for (field, value) in BUILD_CONFIG_FIELDS.iter().zip(build_config) {
+        vars.push((format!("FURIOSA_{field}"), value));
+    }
+
+    if !options.data_hash_dirs.is_empty() {
+        let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default());
+        let dirs: Vec<PathBuf> =
+            options.data_hash_dirs.iter().map(|dir| manifest_dir.join(dir)).collect();
+        for dir in &dirs {
+            println!("cargo:rerun-if-changed={}", dir.display());
+        }
+        vars.push((
+            "FURIOSA_DATA_HASH".to_owned(),
+            datahash::hash_dirs(&dirs, options.hash_algorithm)?,
+        ));
+    }
+
+    println!("cargo:rerun-if-env-changed={PREVIOUS_HASH_VAR}");
+    let changed_components = match non_empty_var(PREVIOUS_HASH_VAR)? {
+        // the history is not available, and the variable is meant for the workspace anyway
+        Some(_) if is_packaged() => placeholder("CHANGED_COMPONENTS")?,
+        Some(previous) => list::format_list(&release::changed_components(&previous)?),
+        None => placeholder("CHANGED_COMPONENTS")?,
+    };
+    vars.push(("FURIOSA_CHANGED_COMPONENTS".to_owned(), changed_components));
+
+    if let Some(name) = &options.symbol_version {
+        vars.push(("FURIOSA_SYMBOL_VERSION".to_owned(), write_version_script(name)?));
+    }
+
+    for field in &options.command_fields {
+        vars.push((format!("FURIOSA_{}", field.name), field.run()?));
+    }
+
+    encode_values(&mut vars, options.max_value_len)?;
+
+    for (name, value) in &vars {
+        println!("cargo:rustc-env={name}={value}");
+        for prefix in &options.prefixes {
+            let name = name.strip_prefix("FURIOSA_").unwrap_or(name);
+            println!("cargo:rustc-env={prefix}{name}={value}");
+        }
+    }
+
+    if let Some(id) = &invocation_id {
+        record_invocation(id, &vars)?;
+    }
+
+    let manifest = release_manifest(&vars).to_string();
+    if let Some(out_dir) = env::var_os("OUT_DIR") {
+        fs::write(Path::new(&out_dir).join(STAMP_FILE_NAME), format_stamp(&vars))?;
+        fs::write(Path::new(&out_dir).join(RELEASE_MANIFEST_FILE_NAME), &manifest)?;
+    }
+    if let Some(path) = &options.release_manifest {
+        fs::write(path, &manifest)
+            .map_err(|e| format!("Failed to write the release manifest {}: {e}", path.display()))?;
+    }
+    // the summary is a by-product, which must not fail the build
+    if let Err(e) = summary::update_summary(&vars) {
+        println!("cargo:warning=Failed to update the furiosa-metadata summary: {e}");
+    }
+
+    Ok(())
+}
+
+/// Escapes and truncates the values, as every value ends up in a line of cargo directives and
+/// the stamp file, and adds `FURIOSA_METADATA_TRUNCATED`.
+fn encode_values(
+    vars: &mut Vec<(String, String)>,
+    max_value_len: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut truncated = Vec::new();
+    for (name, value) in vars.iter_mut() {
+        if !is_valid_name(name) {
+            return Err(format!("Invalid variable name {name:?}").into());
+        }
+        let truncate = if LIST_VARS.contains(&name.as_str()) {
+            // already escaped by `list::format_list`, in a way that `list::parse_list` reverses
+            |value: &mut String, max_len| list::truncate_list(value, max_len, TRUNCATION_MARKER)
+        } else {
+            *value = escape_value(value);
+            truncate_value
+        };
+        if truncate(value, max_value_len) {
+            eprintln!("[furiosa-metadata] Truncated {name} to {max_value_len} bytes.");
+            truncated.push(name.strip_prefix("FURIOSA_").unwrap_or(name).to_owned());
+        }
+    }
+    vars.push(("FURIOSA_METADATA_TRUNCATED".to_owned(), list::format_list(&truncated)));
+    Ok(())
+}
+
+const RELEASE_MANIFEST_FILE_NAME: &str = "release-manifest.json";
+
+/// Builds the release manifest fragment from the variables to be set.
+///
+/// The `artifacts` list is left empty for the release automation to fill in.
+fn release_manifest(vars: &[(String, String)]) -> json::Value {
+    let var = |name: &str| vars.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+    json::Value::Object(vec![
+        ("name".to_owned(), env::var("CARGO_PKG_NAME").ok().into()),
+        ("version".to_owned(), env::var("CARGO_PKG_VERSION").ok().into()),
+        ("git_short_hash".to_owned(), var("FURIOSA_GIT_SHORT_HASH").into()),
+        ("channel".to_owned(), var("FURIOSA_CHANNEL").into()),
+        ("build_timestamp".to_owned(), var("FURIOSA_BUILD_TIMESTAMP").into()),
+        ("artifacts".to_owned(), json::Value::Array(vec![])),
+    ])
+}
+
+const VERSION_SCRIPT_FILE_NAME: &str = "version-script.map";
+
+/// Writes a linker version script defining the symbol version `<name>_<package version>`
+/// to `$OUT_DIR/version-script.map`, passes it to the linker of a `cdylib` on Linux and sets
+/// `cfg(furiosa_symbol_versions)` for [`symbol_versions!`]. Returns the symbol version.
+///
+/// The cfg applies to every target of the package, so the `cdylib` must be linked with lld, which
+/// accepts `.symver` in test binaries and `rlib`s as well. GNU ld fails to link either.
+fn write_version_script(name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if !is_valid_name(name) {
+        return Err(format!("Invalid symbol version name {name:?}").into());
+    }
+    let node = symbol_version_node(name, &env::var("CARGO_PKG_VERSION")?);
+    let rustc_version = rustc_version().and_then(|version| parse_rustc_version(&version));
+    if rustc_version >= Some((1, 80)) {
+        println!("cargo:rustc-check-cfg=cfg(furiosa_symbol_versions)");
+    }
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("linux") && builds_cdylib()? {
+        let linker = env::var("RUSTC_LINKER").ok();
+        let rustflags = env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+        let target = env::var("TARGET").unwrap_or_default();
+        if !links_with_lld(linker.as_deref(), &rustflags, &target, rustc_version) {
+            return Err(format!(
+                "Symbol version {node} requires the cdylib to be linked with lld, as GNU ld \
+                 can't combine it with the version script of rustc \
+                 (e.g. set `-C link-arg=-fuse-ld=lld` in RUSTFLAGS)"
+            )
+            .into());
+        }
+        let path = Path::new(&env::var_os("OUT_DIR").ok_or("OUT_DIR is not set")?)
+            .join(VERSION_SCRIPT_FILE_NAME);
+        // symbols are assigned by `symbol_versions!`, as rustc passes its own version script
+        // which would take precedence over this one
+        fs::write(&path, format!("{node} {{\n}};\n"))?;
+        println!("cargo:rustc-cdylib-link-arg=-Wl,--version-script={}", path.display());
+        println!("cargo:rustc-cfg=furiosa_symbol_versions");
+    }
+    Ok(node)
+}
+
+/// Returns true if the package being built has a `cdylib` target.
+fn builds_cdylib() -> Result<bool, Box<dyn std::error::Error>> {
+    let manifest_dir = env::var_os("CARGO_MANIFEST_DIR").ok_or("CARGO_MANIFEST_DIR is not set")?;
+    let manifest_path = Path::new(&manifest_dir).join("Cargo.toml");
+    let program = cargo();
+    let mut args = vec!["metadata", "--no-deps", "--format-version=1"];
+    if is_offline() {
+        args.push("--offline");
+    }
+    let cmd_line = format!(
+        "{} {} --manifest-path {}",
+        program.to_string_lossy(),
+        args.join(" "),
+        manifest_path.display()
+    );
+    let mut command = Command::new(program);
+    command.args(&args).arg("--manifest-path").arg(&manifest_path);
+    let metadata = run_command(command, &cmd_line, json::parse)?;
+
+    let name = env::var("CARGO_PKG_NAME")?;
+    let package = metadata
+        .get("packages")
+        .and_then(json::Value::as_array)
+        .and_then(|packages| {
+            packages.iter().find(|package| {
+                package.get("name").and_then(json::Value::as_str) == Some(name.as_str())
+            })
+        })
+        .ok_or_else(|| format!("No package {name} in the output of `{cmd_line}`"))?;
+    let targets = package.get("targets").and_then(json::Value::as_array).unwrap_or_default();
+    Ok(targets.iter().any(|target| {
+        let crate_types = target.get("crate_types").and_then(json::Value::as_array);
+        crate_types.unwrap_or_default().iter().any(|t| t.as_str() == Some("cdylib"))
+    }))
+}
+
+/// Returns true if the target is linked with lld, as set by the linker (e.g. `rust-lld`) or
+/// the rustflags (e.g. `-C link-arg=-fuse-ld=lld`), or by default as for
+/// `x86_64-unknown-linux-gnu` since Rust 1.90. `rustflags` are separated by `\x1f`, as in
+/// `CARGO_ENCODED_RUSTFLAGS`.
+fn links_with_lld(
+    linker: Option<&str>,
+    rustflags: &str,
+    target: &str,
+    rustc_version: Option<(u32, u32)>,
+) -> bool {
+    let flags: Vec<&str> = rustflags.split('\x1f').collect();
+    if flags.iter().any(|flag| flag.ends_with("linker-features=-lld")) {
+        return false;
+    }
+    let file_name = |path: &str| Path::new(path).file_name().map(|name| name.to_owned());
+    linker.and_then(file_name).map_or(false, |name| name.to_string_lossy().contains("lld"))
+        || flags.iter().any(|flag| flag.contains("lld") || flag.ends_with("self-contained=+linker"))
+        || (target == "x86_64-unknown-linux-gnu" && rustc_version >= Some((1, 90)))
+}
+
+/// Returns the symbol version for `version`, where `-` and `+` are replaced with `_`,
+/// as the assembler doesn't accept them in `.symver`.
+fn symbol_version_node(name: &str, version: &str) -> String {
+    let version: String = version
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '_' })
+        .collect();
+    format!("{name}_{version}")
+}
+
+/// Channels that are always accepted by `cfg(furiosa_channel = "...")` even when not in use.
+const KNOWN_CHANNELS: &[&str] = &["release", "nightly", "beta", "alpha", "rc", "dev", "prerelease"];
+
+/// Sets `cfg(furiosa_channel = "<channel>")` for the crate being built.
+fn emit_channel_cfg(channel: &str) {
+    println!("cargo:rustc-cfg=furiosa_channel=\"{channel}\"");
+
+    // `rustc-check-cfg` is stable since 1.80, and older versions complain about it
+    if rustc_version().and_then(|version| parse_rustc_version(&version)) >= Some((1, 80)) {
+        let mut channels = KNOWN_CHANNELS.to_vec();
+        if !channels.contains(&channel) {
+            channels.push(channel);
+        }
+        let values: Vec<String> = channels.iter().map(|c| format!("\"{c}\"")).collect();
+        println!("cargo:rustc-check-cfg=cfg(furiosa_channel, values({}))", values.join(", "));
+    }
+}
+
+/// Returns the output of `rustc --version` for the compiler building the crate, if available.
+fn rustc_version() -> Option<String> {
+    let output = Command::new(env::var_os("RUSTC")?).arg("--version").output().ok()?;
+    let version = str::from_utf8(&output.stdout).ok()?.trim();
+    (output.status.success() && !version.is_empty()).then(|| version.to_owned())
+}
+
+/// Parses the major and minor versions from the output of `rustc --version`.
+fn parse_rustc_version(version: &str) -> Option<(u32, u32)> {
+    let version = version.strip_prefix("rustc ")?;
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+const TIMINGS_VAR: &str = "FURIOSA_METADATA_TIMINGS";
+
+/// Returns an identifier of the current cargo invocation, shared by all build scripts it runs.
+///
+/// Build scripts are directly run by cargo, so on Unix this is derived from the parent process id
+/// and (on Linux) its start time, which distinguishes process ids reused by later invocations.
+/// Elsewhere it is derived from the jobserver passed in `CARGO_MAKEFLAGS`, which is a semaphore
+/// with a random name created by each invocation on Windows. Returns `None` if neither is known.
+fn invocation_id() -> Option<String> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    #[cfg(unix)]
+    {
+        let ppid = std::os::unix::process::parent_id();
+        // the 22nd field of `/proc/<pid>/stat`, after the command name in parentheses
+        let start_time = fs::read_to_string(format!("/proc/{ppid}/stat")).ok().and_then(|stat| {
+            let (_, fields) = stat.rsplit_once(')')?;
+            fields.split_whitespace().nth(19).map(str::to_owned)
+        });
+        (ppid, start_time).hash(&mut hasher);
+    }
+    #[cfg(not(unix))]
+    {
+        let makeflags = env::var("CARGO_MAKEFLAGS").ok()?;
+        jobserver_auth(&makeflags)?.hash(&mut hasher);
+    }
+    Some(format!("{:016x}", hasher.finish()))
+}
+
+/// Returns the jobserver of `CARGO_MAKEFLAGS`, e.g. `__rust_jobserver_semaphore_3740925394`
+/// on Windows for `-j --jobserver-fds=__rust_jobserver_semaphore_3740925394 --jobserver-auth=...`.
+#[cfg_attr(unix, allow(dead_code))]
+fn jobserver_auth(makeflags: &str) -> Option<&str> {
+    makeflags.split_whitespace().rev().find_map(|flag| {
+        flag.strip_prefix("--jobserver-auth=").or_else(|| flag.strip_prefix("--jobserver-fds="))
+    })
+}
+
+/// Warns about release tags at HEAD disagreeing with the version being built,
+/// which are rejected by the pre-push hook of [`release::install_pre_push_hook`].
+fn check_head_tags() -> Result<(), Box<dyn std::error::Error>> {
+    let name = env::var("CARGO_PKG_NAME")?;
+    let version = env::var("CARGO_PKG_VERSION")?;
+    let manifest_dir = env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let root = Path::new(&manifest_dir) == Path::new(&get_workspace_dir()?);
+    for tag in release::head_tags()? {
+        if let Some(mismatch) = release::tag_mismatch(&tag, &name, root, &version) {
+            println!("cargo:warning={mismatch}");
+        }
+    }
+    Ok(())
+}
+
+const PREVIOUS_HASH_VAR: &str = "FURIOSA_METADATA_PREVIOUS_HASH";
+
+const BUILDER_SALT_VAR: &str = "FURIOSA_METADATA_BUILDER_SALT";
+
+/// Returns the host name and the user name of the build machine.
+fn builder_identity() -> Result<(String, String), Box<dyn std::error::Error>> {
+    let hostname = ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .or_else(|| env::var("HOSTNAME").ok())
+        .or_else(|| env::var("COMPUTERNAME").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .ok_or("host name is not available")?;
+    // often unset in containers, which have distinct host names anyway
+    let user = env::var("USER").or_else(|_| env::var("USERNAME")).unwrap_or_default();
+    Ok((hostname, user))
+}
+
+/// Returns a salted hash of the build machine, which is stable but doesn't reveal the machine.
+fn builder_fingerprint(
+    algorithm: HashAlgorithm,
+    salt: &str,
+    (hostname, user): &(String, String),
+) -> String {
+    algorithm.fingerprint(&[salt, hostname, user])
+}
+
+/// Returns the first 16 hex digits of the SHA-256 digest of NUL-terminated `parts`.
+fn short_digest(parts: &[&str]) -> String {
+    let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update(&[0]);
+    }
+    hasher.finalize_hex()[..16].to_owned()
+}
+
+/// The hash algorithm of the fingerprints computed by this crate, i.e. `FURIOSA_DATA_HASH`,
+/// `FURIOSA_BUILD_CONFIG_FINGERPRINT` and `FURIOSA_BUILDER_FINGERPRINT`.
+///
+/// Each fingerprint is prefixed with the name of the algorithm (e.g. `sha256:0123456789abcdef`),
+/// so that it can be recomputed elsewhere.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum HashAlgorithm {
+    /// SHA-256, the default.
+    #[default]
+    Sha256,
+    /// BLAKE3, which is much faster for large data directories. Requires the `blake3` feature.
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Returns the name used in the fingerprints, e.g. `sha256`.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Returns the fingerprint of NUL-terminated `parts`, e.g. `sha256:0123456789abcdef`.
+    pub(crate) fn fingerprint(self, parts: &[&str]) -> String {
+        let mut hasher = Hasher::new(self);
+        for part in parts {
+            hasher.update(part.as_bytes());
+            hasher.update(&[0]);
+        }
+        format!("{}:{}", self.name(), &hasher.finalize_hex()[..16])
+    }
+}
+
+/// A streaming hasher with the algorithm chosen at runtime.
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub(crate) fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub(crate) fn finalize_hex(self) -> String {
+        let hex = |digest: &[u8]| digest.iter().map(|b| format!("{b:02x}")).collect();
+        match self {
+            Hasher::Sha256(hasher) => hex(&hasher.finalize()),
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(hasher) => hex(hasher.finalize().as_bytes()),
+        }
+    }
+}
+
+const BUILD_CONFIG_FIELDS: [&str; 5] = [
+    "BUILD_FEATURES",
+    "BUILD_PROFILE",
+    "BUILD_TARGET",
+    "RUSTC_VERSION",
+    "BUILD_CONFIG_FINGERPRINT",
+];
+
+/// Returns the build configuration fields in the order of [`BUILD_CONFIG_FIELDS`].
+fn build_config(algorithm: HashAlgorithm) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    // cargo passes `foo-bar` as `CARGO_FEATURE_FOO_BAR`, so the original name is lost
+    let mut features: Vec<String> = env::vars_os()
+        .filter_map(|(name, _)| {
+            Some(name.to_str()?.strip_prefix("CARGO_FEATURE_")?.to_ascii_lowercase())
+        })
+        .collect();
+    features.sort();
+
+    let mut config = vec![
+        list::format_list(&features),
+        env::var("PROFILE")?,
+        env::var("TARGET")?,
+        rustc_version().ok_or("rustc version is not available")?,
+    ];
+    let parts: Vec<&str> = config.iter().map(String::as_str).collect();
+    config.push(algorithm.fingerprint(&parts));
+    Ok(config)
+}
+
+/// Records the variables set for this crate next to the cargo timing reports,
+/// i.e. `<target dir>/cargo-timings/furiosa-metadata-<invocation id>/<package>.json`.
+fn record_invocation(
+    invocation_id: &str,
+    vars: &[(String, String)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let package = env::var("CARGO_PKG_NAME")?;
+    let dir = target_dir()?.join("cargo-timings").join(format!("furiosa-metadata-{invocation_id}"));
+    fs::create_dir_all(&dir)?;
+
+    let record = json::Value::Object(vec![
+        ("invocation_id".to_owned(), invocation_id.into()),
+        ("package".to_owned(), package.as_str().into()),
+        ("out_dir".to_owned(), env::var("OUT_DIR").ok().into()),
+        (
+            "vars".to_owned(),
+            json::Value::Object(
+                vars.iter().map(|(name, value)| (name.clone(), value.as_str().into())).collect(),
+            ),
+        ),
+    ]);
+    fs::write(dir.join(format!("{package}.json")), record.to_string())?;
+    Ok(())
+}
+
+/// Returns the cargo target directory of the current build.
+///
+/// Cargo puts `CACHEDIR.TAG` at the root of the target directory, which is an ancestor of
+/// `OUT_DIR`. Falls back to `target` in the workspace directory.
+fn target_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Some(out_dir) = env::var_os("OUT_DIR") {
+        if let Some(dir) =
+            Path::new(&out_dir).ancestors().find(|d| d.join("CACHEDIR.TAG").is_file())
+        {
+            return Ok(dir.to_owned());
+        }
+    }
+    Ok(Path::new(&get_workspace_dir()?).join("target"))
+}
+
+/// Writes a file shared between build scripts, which run in parallel,
+/// so that a partially written file is never exposed.
+fn write_atomically(path: &Path, contents: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let temp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Returns the release channel, either overridden or derived from the package version.
+fn release_channel() -> Result<String, Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-env-changed={CHANNEL_VAR}");
+    match env::var(CHANNEL_VAR) {
+        Ok(channel) => {
+            if !is_valid_channel(&channel) {
+                return Err(format!("{CHANNEL_VAR} contains an invalid channel {channel:?}").into());
+            }
+            Ok(channel)
+        }
+        Err(VarError::NotPresent) => {
+            Ok(default_release_channel(&env::var("CARGO_PKG_VERSION_PRE").unwrap_or_default()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+const CHANNEL_VAR: &str = "FURIOSA_METADATA_CHANNEL";
+
+fn is_valid_channel(channel: &str) -> bool {
+    !channel.is_empty() && channel.bytes().all(|c| matches!(c, b'a'..=b'z' | b'0'..=b'9' | b'-'))
+}
+
+fn default_release_channel(pre: &str) -> String {
+    if pre.is_empty() {
+        return "release".to_owned();
+    }
+    let channel: String = pre
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    if channel.is_empty() {
+        "prerelease".to_owned()
+    } else {
+        channel
+    }
+}
+
+/// Where the value of a field came from, recorded in `FURIOSA_METADATA_SOURCE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    /// Computed from the git repository.
+    Git,
+    /// Injected via an environment variable.
+    Env,
+    /// Taken from the system clock.
+    Clock,
+    /// Taken from `SOURCE_DATE_EPOCH`.
+    SourceDateEpoch,
+    /// Taken from the committer date of HEAD.
+    CommitDate,
+    /// Deliberately not computed.
+    Skipped,
+    /// Read from the repository directly, as git is not installed.
+    GitDir,
+    /// Read from `.cargo_vcs_info.json`.
+    VcsInfo,
+    /// Read from a fallback hash file.
+    File,
+    /// Not available, so replaced with a placeholder (see [`placeholder`]).
+    Placeholder,
+    /// Not available, e.g. the dirtiness of an injected hash.
+    Unknown,
+}
+
+impl Source {
+    fn name(self) -> &'static str {
+        match self {
+            Source::Git => "git",
+            Source::Env => "env",
+            Source::Clock => "clock",
+            Source::SourceDateEpoch => "source-date-epoch",
+            Source::CommitDate => "commit-date",
+            Source::Skipped => "skipped",
+            Source::GitDir => "git-dir",
+            Source::VcsInfo => "vcs-info",
+            Source::File => "file",
+            Source::Placeholder => "placeholder",
+            Source::Unknown => "unknown",
+        }
+    }
+}
+
+const STAMP_FILE_NAME: &str = "furiosa-metadata.env";
+
+/// Reads the metadata set by [`set_metadata_env_vars`] for the crate being compiled.
+///
+/// This is designed to be used by proc-macro crates at expansion time, where the environment
+/// variables set by the consumer's build script are not visible to the proc-macro itself.
+/// The build script also writes all variables into a stamp file under its `OUT_DIR`,
+/// which is also visible to the proc-macro during the expansion, so the consumer crate should
+/// call [`set_metadata_env_vars`] from its own build script.
+///
+/// The returned map is keyed by the environment variable name, e.g. `FURIOSA_GIT_SHORT_HASH`.
+pub fn stamped_metadata() -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    let out_dir = env::var_os("OUT_DIR").ok_or(
+        "OUT_DIR is not set; does the crate being compiled have a build script \
+         calling furiosa_metadata::set_metadata_env_vars()?",
+    )?;
+    let path = Path::new(&out_dir).join(STAMP_FILE_NAME);
+    let stamp = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read the stamp file {}: {e}", path.display()))?;
+    Ok(parse_stamp(&stamp)?)
+}
+
+/// Returns true if `name` consists of `A-Z`, `0-9` and `_`.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|c| matches!(c, b'A'..=b'Z' | b'0'..=b'9' | b'_'))
+}
+
+/// Escapes control characters in `value` as in Rust (e.g. `\n`), so that a value coming from
+/// a branch name or a command output can't inject another cargo directive or stamp file line.
+///
+/// This is only for display, as `\` is left as is and the escaping can't be reversed.
+/// List values (see [`LIST_VARS`]) are escaped by [`list::format_list`] instead, which can.
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_control() {
+            escaped.extend(c.escape_default());
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+const TRUNCATION_MARKER: &str = "...[truncated]";
+
+/// The variables encoded as described in [`list`], which are truncated between items.
+/// Their values must come from [`list::format_list`].
+const LIST_VARS: [&str; 2] = ["FURIOSA_BUILD_FEATURES", "FURIOSA_CHANGED_COMPONENTS"];
+
+/// Truncates `value` to `max_len` bytes, ending with [`TRUNCATION_MARKER`].
+/// Returns true if truncated.
+fn truncate_value(value: &mut String, max_len: usize) -> bool {
+    if value.len() <= max_len {
+        return false;
+    }
+    let mut end = max_len - TRUNCATION_MARKER.len();
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+    value.push_str(TRUNCATION_MARKER);
+    true
+}
+
+fn format_stamp(vars: &[(String, String)]) -> String {
+    vars.iter().map(|(name, value)| format!("{name}={value}\n")).collect()
+}
+
+fn parse_stamp(stamp: &str) -> Result<BTreeMap<String, String>, String> {
+    stamp
+        .lines()
+        .map(|line| match line.split_once('=') {
+            Some((name, value)) => Ok((name.to_owned(), value.to_owned())),
+            None => Err(format!("Malformed line in the stamp file: {line:?}")),
+        })
+        .collect()
+}
+
+/// Options for [`set_metadata_env_vars_with`].
+///
+/// ```no_run
+/// furiosa_metadata::set_metadata_env_vars_with(
+///     &furiosa_metadata::MetadataOptions::default().command_field(
+///         "FIRMWARE_VERSION",
+///         "firmware-tool",
+///         &["--version"],
+///         r"^firmware-tool (\d+\.\d+\.\d+)$",
+///     ),
+/// )
+/// .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct MetadataOptions {
+    command_fields: Vec<CommandField>,
+    release_manifest: Option<PathBuf>,
+    fallback_hash_files: Vec<PathBuf>,
+    allow_missing_git: bool,
+    prefixes: Vec<String>,
+    include_build_config: bool,
+    max_value_len: usize,
+    data_hash_dirs: Vec<PathBuf>,
+    hash_algorithm: HashAlgorithm,
+    symbol_version: Option<String>,
+    check_release_tags: bool,
+}
+
+impl Default for MetadataOptions {
+    fn default() -> Self {
+        MetadataOptions {
+            command_fields: Vec::new(),
+            release_manifest: None,
+            fallback_hash_files: Vec::new(),
+            allow_missing_git: false,
+            prefixes: Vec::new(),
+            include_build_config: false,
+            max_value_len: 4096,
+            data_hash_dirs: Vec::new(),
+            hash_algorithm: HashAlgorithm::default(),
+            symbol_version: None,
+            check_release_tags: false,
+        }
+    }
+}
+
+impl MetadataOptions {
+    /// Uses a placeholder (see [`set_metadata_env_vars`]) for the hash if it can't be determined
+    /// from git or any fallback file, instead of failing the build. Defaults to false.
+    pub fn allow_missing_git(mut self, allow: bool) -> Self {
+        self.allow_missing_git = allow;
+        self
+    }
+
+    /// Adds a file to read the hash from when git is not available, e.g. when the crate is built
+    /// from a source tarball or in a hermetic environment without `.git` or the `git` binary.
+    ///
+    /// The path is relative to the package directory, and files are tried in the order added.
+    /// A file named `.cargo_vcs_info.json` is read as written by `cargo package`,
+    /// and any other file should contain the short hash, optionally with a `-modified` suffix.
+    /// Missing files are skipped.
+    pub fn fallback_hash_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.fallback_hash_files.push(path.into());
+        self
+    }
+
+    /// Limits the length of every value to `len` bytes, so that an unexpectedly large value
+    /// (e.g. a command output) doesn't bloat the binary or exceed the environment size limits.
+    /// Defaults to 4096.
+    ///
+    /// A longer value is cut to end with `...[truncated]`, and listed in
+    /// `FURIOSA_METADATA_TRUNCATED` (e.g. `GIT_DESCRIBE;FIRMWARE_VERSION`, empty if none),
+    /// encoded as described in [`list`]. A list value (e.g. `FURIOSA_BUILD_FEATURES`) is cut
+    /// between items instead, and ends with a `...[truncated]` item so that it can still be parsed.
+    pub fn max_value_len(mut self, len: usize) -> Self {
+        self.max_value_len = len;
+        self
+    }
+
+    /// Sets the build configuration for [`build_config_constants!`], which are placeholders
+    /// otherwise. Defaults to false.
+    pub fn include_build_config(mut self, include: bool) -> Self {
+        self.include_build_config = include;
+        self
+    }
+
+    /// Also sets every variable with given prefix in place of `FURIOSA_`, e.g.
+    /// `PRODUCTX_GIT_SHORT_HASH` for `PRODUCTX_`, where `prefix` consists of `A-Z`, `0-9` and `_`.
+    ///
+    /// The `FURIOSA_` variables are always set, as the macros of this crate depend on them.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefixes.push(prefix.into());
+        self
+    }
+
+    /// Also writes the release manifest fragment to given path, relative to the package directory.
+    ///
+    /// The fragment is a JSON object with `name`, `version`, `git_short_hash`, `channel`,
+    /// `build_timestamp` and an empty `artifacts` list for the release automation to fill in.
+    pub fn release_manifest(mut self, path: impl Into<PathBuf>) -> Self {
+        self.release_manifest = Some(path.into());
+        self
+    }
+
+    /// Adds a directory to `FURIOSA_DATA_HASH`, a hash of the paths and contents of all files
+    /// in the directories added, e.g. firmware images shipped with the binary.
+    ///
+    /// The path is relative to the package directory. Enable the `rayon` feature to hash
+    /// the files in parallel, which helps with directories of several gigabytes.
+    pub fn data_hash_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.data_hash_dirs.push(path.into());
+        self
+    }
+
+    /// Sets the hash algorithm of the fingerprints, e.g. `FURIOSA_DATA_HASH`.
+    /// Defaults to [`HashAlgorithm::Sha256`].
+    pub fn hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    /// Versions the symbols of a `cdylib` for Linux with `<name>_<package version>`
+    /// (e.g. `FURIOSA_RT_1.2.3` for `FURIOSA_RT`), where `name` consists of `A-Z`, `0-9` and `_`.
+    ///
+    /// The version is defined in `$OUT_DIR/version-script.map` passed to the linker, and set to
+    /// `FURIOSA_SYMBOL_VERSION`. The symbols to be versioned are listed with
+    /// [`symbol_versions!`]. `-` and `+` in the package version are replaced with `_`.
+    ///
+    /// This requires lld, the default linker of `x86_64-unknown-linux-gnu` since Rust 1.90,
+    /// as GNU ld refuses to combine the version with the anonymous one of rustc's version script.
+    /// The build script fails if the `cdylib` is not linked with lld, e.g. with
+    /// `-C link-arg=-fuse-ld=lld`. An `rlib` built along with the `cdylib` has the versioned
+    /// symbols as well, so the binaries linking it need lld too.
+    pub fn symbol_version(mut self, name: impl Into<String>) -> Self {
+        self.symbol_version = Some(name.into());
+        self
+    }
+
+    /// Warns about release tags at HEAD disagreeing with the package version, as the pre-push
+    /// hook of `furiosa-metadata install-hooks` would reject them. Defaults to false, as this runs
+    /// git and cargo once more in every build script.
+    pub fn check_release_tags(mut self, check: bool) -> Self {
+        self.check_release_tags = check;
+        self
+    }
+
+    /// Adds a field captured from the standard output of a command.
+    ///
+    /// The command is run from the package directory and its trimmed output must match `regex`.
+    /// The output (or the first capture group of `regex`, if any) is set to the
+    /// `FURIOSA_<name>` environment variable, where `name` consists of `A-Z`, `0-9` and `_`.
+    pub fn command_field(mut self, name: &str, program: &str, args: &[&str], regex: &str) -> Self {
+        self.command_fields.push(CommandField {
+            name: name.to_owned(),
+            program: program.to_owned(),
+            args: args.iter().map(|&arg| arg.to_owned()).collect(),
+            regex: regex.to_owned(),
+        });
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CommandField {
+    name: String,
+    program: String,
+    args: Vec<String>,
+    regex: String,
+}
+
+impl CommandField {
+    fn run(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let name = &self.name;
+        if !is_valid_name(name) {
+            return Err(format!("Invalid command field name {name:?}").into());
+        }
+        let regex = Regex::new(&self.regex).map_err(|e| {
+            format!("Command field {name} has an invalid regex {:?}: {e}", self.regex)
+        })?;
+
+        let cmd_line = format!("{} {}", self.program, self.args.join(" "));
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        run_command(command, cmd_line.trim_end(), |s| {
+            let s = s.trim();
+            match regex.captures(s) {
+                Some(captures) => {
+                    let value = captures.get(1).or_else(|| captures.get(0)).unwrap().as_str();
+                    Ok(value.to_owned())
+                }
+                None => Err(format!("output does not match {:?}", regex.as_str())),
+            }
+        })
+    }
+}
+
+const OFFLINE_VAR: &str = "FURIOSA_METADATA_OFFLINE";
+
+/// Returns true if the network-isolation mode (`FURIOSA_METADATA_OFFLINE=1`) is enabled.
+fn is_offline() -> bool {
+    matches!(env::var(OFFLINE_VAR).as_deref(), Ok("1"))
+}
+
+const CEILING_VAR: &str = "FURIOSA_METADATA_GIT_CEILING_DIRECTORIES";
+
+/// Returns the value of `GIT_CEILING_DIRECTORIES` to run git with, if any.
+///
+/// It combines the standard `GIT_CEILING_DIRECTORIES` with our own ceiling directories.
+fn git_ceiling_directories() -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let ceilings = match env::var(CEILING_VAR) {
+        Ok(ceilings) if ceilings.is_empty() => return Ok(None),
+        Ok(ceilings) => ceilings,
+        Err(VarError::NotPresent) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // git silently ignores relative paths, which would make the bound ineffective
+    if let Some(ceiling) = ceilings.split(':').find(|ceiling| !Path::new(ceiling).is_absolute()) {
+        return Err(format!("{CEILING_VAR} contains a non-absolute path {ceiling:?}").into());
+    }
+
+    match env::var("GIT_CEILING_DIRECTORIES") {
+        Ok(existing) if !existing.is_empty() => Ok(Some(format!("{existing}:{ceilings}"))),
+        Ok(_) | Err(VarError::NotPresent) => Ok(Some(ceilings)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+const NO_GIT_RERUN_VAR: &str = "FURIOSA_METADATA_NO_GIT_RERUN";
+
+/// Lets cargo rerun the build script when HEAD moves to another commit or the index is updated,
+/// so that the hash doesn't go stale after committing or switching branches.
+fn emit_git_rerun_if_changed() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-env-changed={NO_GIT_RERUN_VAR}");
+    if matches!(env::var(NO_GIT_RERUN_VAR).as_deref(), Ok("1"))
+        || env::var_os("FURIOSA_GIT_SHORT_HASH").is_some()
+        || is_packaged()
+    {
+        return Ok(());
+    }
+
+    // not being able to watch the repository is not a reason to fail the build
+    match discover_git_dir().and_then(|git_dir| match git_dir {
+        Some(git_dir) => git_dir.watched_paths(),
+        None => Ok(Vec::new()),
+    }) {
+        Ok(paths) => {
+            for path in paths {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
+        Err(e) => eprintln!("[furiosa-metadata] Failed to watch the repository for changes: {e}"),
+    }
+    Ok(())
+}
+
+/// Resolves the hash and, if it came from git, the details.
 ///
-/// * `FURIOSA_GIT_SHORT_HASH`
-/// * `FURIOSA_BUILD_TIMESTAMP`
-///
-/// Following environment variables may be used for configuration:
-///
-/// * `FURIOSA_METADATA_EXPECT_MODIFIED` is a colon-separated list of glob patterns
-///   that are ignored for the dirty repository detection (puts `-modified` to the hash).
-///   Patterns match the full path, so `*.bak` doesn't match `foo/bar.bak` (`**/*.bak` does).
-///   See the `glob` crate documentation for the full pattern syntax.
-pub fn set_metadata_env_vars() -> Result<(), Box<dyn std::error::Error>> {
-    if let Err(VarError::NotPresent) = env::var("FURIOSA_GIT_SHORT_HASH") {
-        let expected_patterns = get_expected_patterns()?;
-        println!("cargo:rustc-env=FURIOSA_GIT_SHORT_HASH={}", git_short_hash(&expected_patterns)?);
+/// These are shared by build scripts if enabled by `FURIOSA_METADATA_CACHE`,
+/// as they only depend on the repository and the environment.
+fn resolve_git_metadata(
+    options: &MetadataOptions,
+) -> Result<(ResolvedHash, Option<GitDetails>), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-env-changed=FURIOSA_GIT_SHORT_HASH");
+    if let Some(hash) = hash_without_repository()? {
+        return Ok((hash, None));
     }
 
-    println!("cargo:rustc-env=FURIOSA_BUILD_TIMESTAMP={}", build_timestamp());
+    let cache = GitCache::open()?;
+    if let Some((hash, details)) = cache.as_ref().and_then(GitCache::load) {
+        // still let cargo know what the cached values depend on
+        patterns::get_policy_patterns()?;
+        return Ok((hash, Some(details)));
+    }
 
-    Ok(())
+    // every git command is run from the workspace directory, so locate it only once
+    let workspace_dir = get_workspace_dir()?;
+    let hash = resolve_git_hash(&workspace_dir, options)?;
+    // the hash may come from an environment without git, so don't try further
+    let details =
+        if hash.hash_source == Source::Git { Some(git_details(&workspace_dir)?) } else { None };
+    if let (Some(cache), Some(details)) = (&cache, &details) {
+        cache.store(&hash, details)?;
+    }
+    Ok((hash, details))
 }
 
-fn get_expected_patterns() -> Result<Vec<Pattern>, Box<dyn std::error::Error>> {
-    const PATTERN_VAR: &str = "FURIOSA_METADATA_EXPECT_MODIFIED";
-
-    println!("cargo:rerun-if-env-changed={PATTERN_VAR}");
-    match env::var(PATTERN_VAR) {
-        Ok(patterns) if patterns.is_empty() => Ok(vec![]),
-        Ok(patterns) => patterns
-            .split(':')
-            .map(|pattern| {
-                if pattern.is_empty() {
-                    Err(format!("{PATTERN_VAR} contains an empty pattern").into())
-                } else {
-                    Pattern::new(pattern).map_err(|e| {
-                        format!("{PATTERN_VAR} contains an invalid pattern {pattern:?}: {e}").into()
-                    })
-                }
-            })
-            .collect(),
-        Err(VarError::NotPresent) => Ok(vec![]),
+/// The resolved Git short hash and where it came from.
+struct ResolvedHash {
+    short_hash: String,
+    /// The full hash, if it is known without git (e.g. from `.cargo_vcs_info.json`).
+    full_hash: Option<String>,
+    dirty: Option<bool>,
+    hash_source: Source,
+    dirty_source: Source,
+}
+
+/// Returns the hash injected via `FURIOSA_GIT_SHORT_HASH`, or of the package being built
+/// (see [`packaged_hash`]), which don't need the repository.
+fn hash_without_repository() -> Result<Option<ResolvedHash>, Box<dyn std::error::Error>> {
+    match env::var("FURIOSA_GIT_SHORT_HASH") {
+        Ok(short_hash) => {
+            return Ok(Some(ResolvedHash {
+                short_hash,
+                full_hash: None,
+                dirty: None,
+                hash_source: Source::Env,
+                dirty_source: Source::Unknown,
+            }));
+        }
+        Err(VarError::NotPresent) => {}
         Err(e) => return Err(e.into()),
     }
+
+    let manifest_dir = env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let hash = packaged_hash(Path::new(&manifest_dir))?;
+    if hash.is_some() {
+        eprintln!(
+            "[furiosa-metadata] Read the hash from {VCS_INFO_FILE_NAME} \
+             as the crate is being built from a package."
+        );
+    }
+    Ok(hash)
+}
+
+/// Returns the Git short hash for the current branch of the npu-tools repository, running git
+/// from `workspace_dir`.
+///
+/// The hash will have a `-modified` suffix if the repository is dirty (see [`git_dirty`]).
+/// If git is not available, the hash is read from fallback files or replaced with a placeholder,
+/// depending on `options`.
+fn resolve_git_hash(
+    workspace_dir: &str,
+    options: &MetadataOptions,
+) -> Result<ResolvedHash, Box<dyn std::error::Error>> {
+    let mut short_hash = match unless_unborn(workspace_dir, git_head_short_hash(workspace_dir)) {
+        Ok(Some(hash)) => hash,
+        Ok(None) => {
+            eprintln!("[furiosa-metadata] HEAD has no commit yet, using a placeholder hash.");
+            return placeholder_hash();
+        }
+        Err(e) if git_is_missing() => match read_head_without_git()? {
+            Some(hash) => return Ok(hash),
+            None => return fallback_git_hash(options, e),
+        },
+        Err(e) => return fallback_git_hash(options, e),
+    };
+
+    let dirty = git_dirty(workspace_dir, &patterns::get_policy_patterns()?)?;
+    if dirty {
+        short_hash.push_str(hash::DIRTY_SUFFIX);
+    }
+    Ok(ResolvedHash {
+        short_hash,
+        full_hash: None,
+        dirty: Some(dirty),
+        hash_source: Source::Git,
+        dirty_source: Source::Git,
+    })
+}
+
+/// Returns true if git is not installed, as opposed to failing for other reasons.
+fn git_is_missing() -> bool {
+    matches!(
+        Command::new("git").arg("--version").output(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound
+    )
 }
 
-/// Returns the Git short hash for the current branch of the npu-tools repository.
+/// Reads HEAD from the repository directly, or returns `None` if there is no repository.
 ///
-/// The hash will have a `-modified` suffix if the repository is dirty.
-/// A repository is considered clean if all updated paths (if any) match any `expected_patterns`.
-fn git_short_hash(expected_patterns: &[Pattern]) -> Result<String, Box<dyn std::error::Error>> {
-    let mut git_short_hash = run_git(
+/// This can't tell whether the repository is dirty, nor describe HEAD.
+fn read_head_without_git() -> Result<Option<ResolvedHash>, Box<dyn std::error::Error>> {
+    let Some(git_dir) = discover_git_dir()? else {
+        return Ok(None);
+    };
+    let Some(hash) = git_dir.head_commit()? else {
+        eprintln!("[furiosa-metadata] HEAD has no commit yet, using a placeholder hash.");
+        return placeholder_hash().map(Some);
+    };
+
+    eprintln!(
+        "[furiosa-metadata] Read HEAD from {} as git is not installed. \
+         Skipped the dirty repository detection.",
+        git_dir.git_dir.display(),
+    );
+    Ok(Some(ResolvedHash {
+        short_hash: hash[..9].to_owned(),
+        full_hash: Some(hash),
+        dirty: None,
+        hash_source: Source::GitDir,
+        dirty_source: Source::Skipped,
+    }))
+}
+
+/// Finds the repository of the crate being built without git, respecting the ceiling directories.
+fn discover_git_dir() -> Result<Option<GitDir>, Box<dyn std::error::Error>> {
+    let dir = env::var_os("CARGO_MANIFEST_DIR").ok_or("CARGO_MANIFEST_DIR is not set")?;
+    let ceilings = match git_ceiling_directories()? {
+        Some(ceilings) => ceilings,
+        None => env::var("GIT_CEILING_DIRECTORIES").unwrap_or_default(),
+    };
+    let ceilings: Vec<&Path> =
+        ceilings.split(':').filter(|ceiling| !ceiling.is_empty()).map(Path::new).collect();
+    GitDir::discover(Path::new(&dir), &ceilings)
+}
+
+fn placeholder_hash() -> Result<ResolvedHash, Box<dyn std::error::Error>> {
+    Ok(ResolvedHash {
+        short_hash: placeholder("GIT_SHORT_HASH")?,
+        full_hash: None,
+        dirty: None,
+        hash_source: Source::Placeholder,
+        dirty_source: Source::Skipped,
+    })
+}
+
+/// Tries the fallback hash files and then the placeholder, if allowed by `options`.
+/// Returns `git_error` otherwise.
+fn fallback_git_hash(
+    options: &MetadataOptions,
+    git_error: Box<dyn std::error::Error>,
+) -> Result<ResolvedHash, Box<dyn std::error::Error>> {
+    for path in &options.fallback_hash_files {
+        if let Some(hash) = read_fallback_hash(path)? {
+            eprintln!(
+                "[furiosa-metadata] Read the hash from {} as git is not available: {git_error}",
+                path.display(),
+            );
+            return Ok(hash);
+        }
+    }
+
+    if options.allow_missing_git {
+        eprintln!(
+            "[furiosa-metadata] Using a placeholder hash as git is not available: {git_error}"
+        );
+        return placeholder_hash();
+    }
+
+    Err(git_error)
+}
+
+/// Reads the hash from a fallback file, or returns `None` if it doesn't exist.
+fn read_fallback_hash(path: &Path) -> Result<Option<ResolvedHash>, Box<dyn std::error::Error>> {
+    let path = match env::var_os("CARGO_MANIFEST_DIR") {
+        Some(dir) => Path::new(&dir).join(path),
+        None => path.to_owned(),
+    };
+    println!("cargo:rerun-if-changed={}", path.display());
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {e}", path.display()).into()),
+    };
+
+    let hash = if path.file_name() == Some(VCS_INFO_FILE_NAME.as_ref()) {
+        parse_vcs_info(&contents)
+    } else {
+        parse_hash_file(&contents)
+    };
+    Ok(Some(hash.map_err(|e| format!("Unexpected contents in {}: {e}", path.display()))?))
+}
+
+/// Reads the hash from `.cargo_vcs_info.json` in `manifest_dir`, or returns `None` if the crate
+/// is not packaged (see [`is_packaged`]).
+fn packaged_hash(manifest_dir: &Path) -> Result<Option<ResolvedHash>, Box<dyn std::error::Error>> {
+    let path = manifest_dir.join(VCS_INFO_FILE_NAME);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {e}", path.display()).into()),
+    };
+    let hash = parse_vcs_info(&contents)
+        .map_err(|e| format!("Unexpected contents in {}: {e}", path.display()))?;
+    Ok(Some(hash))
+}
+
+/// Parses `.cargo_vcs_info.json`, e.g. `{"git": {"sha1": "...", "dirty": true}, ...}`.
+fn parse_vcs_info(contents: &str) -> Result<ResolvedHash, String> {
+    let info = json::parse(contents)?;
+    let git = info.get("git").ok_or("no git information")?;
+    let sha1 = git.get("sha1").and_then(json::Value::as_str).ok_or("no commit id")?;
+    if !hash::is_full_hash(sha1) {
+        return Err(format!("bad commit id {sha1:?}"));
+    }
+    // `dirty` only appears when the package was created from a dirty working tree
+    let dirty = git.get("dirty").and_then(json::Value::as_bool).unwrap_or(false);
+
+    let mut short_hash = sha1[..9].to_owned();
+    if dirty {
+        short_hash.push_str(hash::DIRTY_SUFFIX);
+    }
+    Ok(ResolvedHash {
+        short_hash,
+        full_hash: Some(sha1.to_owned()),
+        dirty: Some(dirty),
+        hash_source: Source::VcsInfo,
+        dirty_source: Source::VcsInfo,
+    })
+}
+
+/// Parses a file containing the short hash, e.g. `0123456789-modified`.
+fn parse_hash_file(contents: &str) -> Result<ResolvedHash, String> {
+    let short_hash = contents.trim();
+    let (_, dirty) = hash::parse_git_hash(short_hash).map_err(|e| e.to_string())?;
+    Ok(ResolvedHash {
+        short_hash: short_hash.to_owned(),
+        full_hash: None,
+        dirty: Some(dirty),
+        hash_source: Source::File,
+        dirty_source: Source::File,
+    })
+}
+
+/// Detailed information about HEAD.
+struct GitDetails {
+    hash: String,
+    /// `None` if HEAD is detached.
+    branch: Option<String>,
+    /// `git describe --tags --always`.
+    describe: String,
+    commit_time: DateTime<Utc>,
+}
+
+fn git_details(workspace_dir: &str) -> Result<GitDetails, Box<dyn std::error::Error>> {
+    let (hash, commit_time) =
+        run_git_in(workspace_dir, &["show", "--no-patch", "--format=%H%n%ct", "HEAD"], |s| {
+            let (hash, time) = s.trim_end().split_once('\n').ok_or("bad output")?;
+            if !hash::is_full_hash(hash) {
+                return Err("bad commit id");
+            }
+            let time = time.parse().ok().and_then(|secs| Utc.timestamp_opt(secs, 0).single());
+            Ok((hash.to_owned(), time.ok_or("bad date")?))
+        })?;
+
+    // prints `HEAD` if detached
+    let branch = run_git_in(workspace_dir, &["rev-parse", "--abbrev-ref", "HEAD"], |s| {
+        match s.trim_end() {
+            "" => Err("empty branch"),
+            "HEAD" => Ok(None),
+            branch => Ok(Some(branch.to_owned())),
+        }
+    })?;
+
+    let describe =
+        run_git_in(workspace_dir, &["describe", "--tags", "--always", "--abbrev=9"], |s| {
+            let s = s.trim_end();
+            if s.is_empty() || s.contains('\n') {
+                Err("bad description")
+            } else {
+                Ok(s.to_owned())
+            }
+        })?;
+
+    Ok(GitDetails { hash, branch, describe, commit_time })
+}
+
+/// Returns the Git short hash for HEAD, without checking whether the repository is dirty.
+fn git_head_short_hash(workspace_dir: &str) -> Result<String, Box<dyn std::error::Error>> {
+    run_git_in(
+        workspace_dir,
         &[
             "rev-parse",
             "--short=9", // guarantee at least 9 letters, for backward compatibility
@@ -87,15 +1711,149 @@ fn git_short_hash(expected_patterns: &[Pattern]) -> Result<String, Box<dyn std::
         ],
         |s| {
             let s = s.trim_end();
-            if s.len() >= 9 && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) {
+            if s.len() >= 9 && hash::is_short_hash(s) {
                 Ok(s.to_owned())
             } else {
                 Err("bad commit id")
             }
         },
-    )?;
+    )
+}
+
+/// Returns `None` if `result` has failed because HEAD has no commit yet,
+/// which is the case for a freshly initialized repository.
+fn unless_unborn<T>(
+    workspace_dir: &str,
+    result: Result<T, Box<dyn std::error::Error>>,
+) -> Result<Option<T>, Box<dyn std::error::Error>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(_) if git_head_is_unborn(workspace_dir) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn git_head_is_unborn(workspace_dir: &str) -> bool {
+    let args =
+        ["status", "--porcelain=v2", "--branch", "--untracked=no", "--ignore-submodules=all"];
+    run_git_in(workspace_dir, &args, |s| {
+        Ok::<_, &str>(s.lines().any(|line| line == "# branch.oid (initial)"))
+    })
+    .unwrap_or(false)
+}
+
+const PLACEHOLDER_VAR: &str = "FURIOSA_METADATA_PLACEHOLDER";
+
+/// Returns the placeholder for a field (e.g. `GIT_SHORT_HASH`) whose value is not available.
+///
+/// This is `FURIOSA_METADATA_PLACEHOLDER_<field>` if set, or `FURIOSA_METADATA_PLACEHOLDER`,
+/// or `unknown` otherwise. It is a list of itself for the fields of [`LIST_VARS`].
+fn placeholder(field: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let placeholder = if DETERMINISTIC {
+        "unknown".to_owned()
+    } else {
+        let field_var = format!("{PLACEHOLDER_VAR}_{field}");
+        println!("cargo:rerun-if-env-changed={field_var}");
+        println!("cargo:rerun-if-env-changed={PLACEHOLDER_VAR}");
+        non_empty_var(&field_var)?
+            .or(non_empty_var(PLACEHOLDER_VAR)?)
+            .unwrap_or_else(|| "unknown".to_owned())
+    };
+    if LIST_VARS.contains(&format!("FURIOSA_{field}").as_str()) {
+        return Ok(list::format_list(&[placeholder]));
+    }
+    Ok(placeholder)
+}
+
+/// Returns true if the crate is being built from a package, e.g. during `cargo package` or
+/// `cargo publish` verification, or from a crates.io download.
+///
+/// Cargo puts `.cargo_vcs_info.json` into every package built from a git repository.
+/// The package is extracted into a temporary directory or vendored into another repository,
+/// where the git status is meaningless (an enclosing repository may report the package directory
+/// itself as untracked or ignored) and HEAD is another commit, or of another repository.
+fn is_packaged() -> bool {
+    env::var_os("CARGO_MANIFEST_DIR")
+        .map_or(false, |dir| Path::new(&dir).join(VCS_INFO_FILE_NAME).is_file())
+}
+
+const VCS_INFO_FILE_NAME: &str = ".cargo_vcs_info.json";
+
+const PATTERN_TRACE_FILE_NAME: &str = "pattern-trace.json";
+
+/// Returns true if the repository is dirty.
+///
+/// A repository is considered clean if all updated paths (if any) match any of non-forbidden
+/// `patterns`. Any updated path matching a forbidden pattern is an error.
+///
+/// When run from a build script, every updated path with its matching pattern and verdict is
+/// recorded in `$OUT_DIR/pattern-trace.json`, so that CI can compare them across builds.
+fn git_dirty(
+    workspace_dir: &str,
+    patterns: &[PolicyPattern],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut dirty = false;
+    let mut forbidden = Vec::new();
+    let mut trace = Vec::new();
+    for path in git_updated_paths(workspace_dir)? {
+        let matched = patterns::find_match(patterns, &path);
+        let verdict = match matched {
+            None => {
+                dirty = true;
+                "dirty"
+            }
+            Some(PolicyPattern { policy: Policy::Ignore, .. }) => "ignored",
+            Some(PolicyPattern { policy: Policy::Expect, .. }) => {
+                eprintln!(
+                    "[furiosa-metadata] Ignored an updated file {path:?} as it was expected."
+                );
+                "expected"
+            }
+            Some(PolicyPattern { policy: Policy::Warn, pattern }) => {
+                println!("cargo:warning=Ignored an updated file {path:?} matching {pattern}");
+                "warned"
+            }
+            Some(PolicyPattern { policy: Policy::Forbid, .. }) => {
+                forbidden.push(path.clone());
+                "forbidden"
+            }
+        };
+        trace.push(json::Value::Object(vec![
+            ("path".to_owned(), path.into()),
+            ("pattern".to_owned(), matched.map(|p| p.pattern.to_string()).into()),
+            ("policy".to_owned(), matched.map(|p| p.policy.name()).into()),
+            ("verdict".to_owned(), verdict.into()),
+        ]));
+    }
+
+    let verdict = match (forbidden.is_empty(), dirty) {
+        (false, _) => "forbidden",
+        (true, true) => "dirty",
+        (true, false) => "clean",
+    };
+    if let Some(out_dir) = env::var_os("OUT_DIR") {
+        let trace = json::Value::Object(vec![
+            ("patterns".to_owned(), patterns::to_json(patterns)),
+            ("paths".to_owned(), json::Value::Array(trace)),
+            ("verdict".to_owned(), verdict.into()),
+        ]);
+        fs::write(Path::new(&out_dir).join(PATTERN_TRACE_FILE_NAME), trace.to_string())?;
+    }
+
+    if !forbidden.is_empty() {
+        let message = format!(
+            "Updated files matching {var} are not allowed: {forbidden:?}",
+            var = Policy::Forbid.var(),
+        );
+        return Err(Failure::new(FailureKind::DirtyForbidden, message).into());
+    }
+    Ok(dirty)
+}
 
-    let dirty = run_git(
+/// Returns all updated paths in the repository, excluding untracked files and submodules.
+fn git_updated_paths(workspace_dir: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    run_git_in(
+        workspace_dir,
         &[
             "status",
             "--untracked=no",          // ignore untracked files (`??`)
@@ -108,7 +1866,7 @@ fn git_short_hash(expected_patterns: &[Pattern]) -> Result<String, Box<dyn std::
             // https://git-scm.com/docs/git-status#_porcelain_format_version_1
             // We can safely assume that the whole output consists of `XY <name>\0`
             // because `--no-renames` prohibits `XY <new name>\0<old name>\0`.
-            let mut dirty = false;
+            let mut paths = Vec::new();
             for line in s.split_terminator('\0') {
                 if line.starts_with("?? ") {
                     return Err("untracked file should have been omitted");
@@ -128,61 +1886,105 @@ fn git_short_hash(expected_patterns: &[Pattern]) -> Result<String, Box<dyn std::
                 ) {
                     return Err("bad status");
                 }
-
-                let path = &line[3..];
-                if expected_patterns.iter().any(|pattern| pattern.matches(path)) {
-                    eprintln!(
-                        "[furiosa-metadata] Ignored an updated file {path:?} as it was expected."
-                    );
-                } else {
-                    dirty = true;
-                }
+                paths.push(line[3..].to_owned());
             }
-            Ok(dirty)
+            Ok(paths)
         },
-    )?;
-
-    if dirty {
-        git_short_hash.push_str("-modified");
-    }
+    )
+}
 
-    Ok(git_short_hash)
+/// Runs `command` and returns its output, whatever the exit status is.
+fn command_output(mut command: Command, cmd_line: &str) -> Result<std::process::Output, Failure> {
+    command.output().map_err(|e| {
+        let kind = if e.kind() == std::io::ErrorKind::NotFound && command.get_program() == "git" {
+            FailureKind::GitMissing
+        } else {
+            FailureKind::CommandFailed
+        };
+        Failure::new(kind, format!("Failed to run `{cmd_line}`: {e}")).with_command(cmd_line, None)
+    })
 }
 
 fn extract_stdout<'a>(
     cmd_line: &'_ str,
     output: &'a std::process::Output,
-) -> Result<&'a str, String> {
+) -> Result<&'a str, Failure> {
     if !output.status.success() {
-        return Err(format!(
+        let message = format!(
             "`{cmd_line}` failed: {status}\n\n{stderr}",
             status = output.status,
             stderr = output.stderr.escape_ascii(),
-        ));
+        );
+        return Err(Failure::new(FailureKind::CommandFailed, message)
+            .with_command(cmd_line, Some(&output.stderr)));
     }
 
     let stdout = str::from_utf8(&output.stdout).map_err(|e| {
-        format!(
+        let message = format!(
             "Unexpected output from `{cmd_line}`: {e}\n\n{stdout}",
             stdout = output.stdout.escape_ascii(),
-        )
+        );
+        Failure::new(FailureKind::CommandFailed, message)
+            .with_command(cmd_line, Some(&output.stderr))
     })?;
 
     Ok(stdout)
 }
 
+/// Returns the cargo to run, which is the one running the build script, or `cargo` in PATH
+/// when run outside cargo, e.g. by the `furiosa-metadata` command.
+fn cargo() -> std::ffi::OsString {
+    env::var_os("CARGO").unwrap_or_else(|| "cargo".into())
+}
+
 fn get_workspace_dir() -> Result<String, Box<dyn std::error::Error>> {
-    let command = env!("CARGO");
-    let args = ["locate-project", "--workspace", "--message-format=plain"];
-    let output = Command::new(command).args(args).output()?;
+    let command = cargo();
+    let mut args = vec!["locate-project", "--workspace", "--message-format=plain"];
+    if is_offline() {
+        args.push("--offline");
+    }
+    let output = Command::new(&command).args(&args).output()?;
 
-    let cmd_line: String = format!("{command} {}", args.join(" "));
+    let cmd_line: String = format!("{} {}", command.to_string_lossy(), args.join(" "));
     let stdout = extract_stdout(&cmd_line, &output)?;
 
     let cargo_path = Path::new(stdout.trim());
     Ok(cargo_path.parent().unwrap().display().to_string())
 }
 
+/// Returns the name and the directory of every package in the workspace, where the directory is
+/// relative to the workspace directory, `/`-separated, and empty for the root package.
+fn workspace_packages() -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let program = cargo();
+    let mut args = vec!["metadata", "--no-deps", "--format-version=1"];
+    if is_offline() {
+        args.push("--offline");
+    }
+    let cmd_line = format!("{} {}", program.to_string_lossy(), args.join(" "));
+    let mut command = Command::new(program);
+    command.args(&args);
+    let metadata = run_command(command, &cmd_line, json::parse)?;
+
+    let workspace_root = metadata
+        .get("workspace_root")
+        .and_then(json::Value::as_str)
+        .ok_or_else(|| format!("No workspace_root in the output of `{cmd_line}`"))?;
+    let packages = metadata
+        .get("packages")
+        .and_then(json::Value::as_array)
+        .ok_or_else(|| format!("No packages in the output of `{cmd_line}`"))?;
+    Ok(packages
+        .iter()
+        .filter_map(|package| {
+            let name = package.get("name")?.as_str()?;
+            let manifest_path = Path::new(package.get("manifest_path")?.as_str()?);
+            let dir = manifest_path.parent()?.strip_prefix(workspace_root).ok()?;
+            let dir: Vec<_> = dir.iter().map(|part| part.to_string_lossy()).collect();
+            Some((name.to_owned(), dir.join("/")))
+        })
+        .collect())
+}
+
 /// Run git with given arguments, as if it was run from the workspace directory,
 /// and try to parse the resulting stdout with given function.
 /// Returns a formatted error with stdout or stderr on any error.
@@ -190,23 +1992,495 @@ fn run_git<T, E: Display>(
     args: &[&str],
     parse: impl Fn(&str) -> Result<T, E>,
 ) -> Result<T, Box<dyn std::error::Error>> {
-    let workspace_dir: String = get_workspace_dir()?;
+    run_git_in(&get_workspace_dir()?, args, parse)
+}
+
+/// Same as [`run_git`], but from `workspace_dir` already located with [`get_workspace_dir`],
+/// as locating it runs cargo.
+fn run_git_in<T, E: Display>(
+    workspace_dir: &str,
+    args: &[&str],
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let (command, cmd_line) = git_command(workspace_dir, args)?;
+    run_command(command, &cmd_line, parse)
+}
+
+/// Runs git like [`run_git`], but for a command answering with the exit status, e.g.
+/// `git merge-base --is-ancestor`. Returns true for 0 and false for 1, and fails otherwise.
+fn run_git_predicate(args: &[&str]) -> Result<bool, Box<dyn std::error::Error>> {
+    let (command, cmd_line) = git_command(&get_workspace_dir()?, args)?;
+    let output = command_output(command, &cmd_line)?;
+    if let Some(code @ (0 | 1)) = output.status.code() {
+        return Ok(code == 0);
+    }
+    // fails with the stderr, as the command has failed
+    extract_stdout(&cmd_line, &output)?;
+    Err(format!("`{cmd_line}` failed: {}", output.status).into())
+}
 
+/// Returns the git command with `args` to run from `workspace_dir`, and its command line.
+fn git_command(
+    workspace_dir: &str,
+    args: &[&str],
+) -> Result<(Command, String), Box<dyn std::error::Error>> {
     let cmd_line = format!("git -C {workspace_dir} {args}", args = args.join(" "));
-    let output = Command::new("git").args(["-C", &workspace_dir]).args(args).output()?;
-    let stdout = extract_stdout(&cmd_line, &output)?;
+    let mut command = Command::new("git");
+    command.args(["-C", workspace_dir]);
+    if is_offline() {
+        command
+            .args(["-c", "protocol.allow=never"]) // reject every transport, including lazy fetches
+            .env("GIT_NO_LAZY_FETCH", "1") // git 2.44+ refuses to fetch missing objects at all
+            .env("GIT_TERMINAL_PROMPT", "0");
+    }
+    if let Some(ceilings) = git_ceiling_directories()? {
+        command.env("GIT_CEILING_DIRECTORIES", ceilings);
+    }
+    // `git status` would otherwise refresh the index, which is watched for rerunning build scripts
+    command.env("GIT_OPTIONAL_LOCKS", "0");
+    command.args(args);
+    Ok((command, cmd_line))
+}
+
+/// Run the given command and try to parse the resulting stdout with given function.
+/// Returns a formatted error with stdout or stderr on any error.
+fn run_command<T, E: Display>(
+    command: Command,
+    cmd_line: &str,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let output = command_output(command, cmd_line)?;
+    let stdout = extract_stdout(cmd_line, &output)?;
+
+    Ok(parse(stdout).map_err(|e| {
+        let message = format!("Unexpected output from `{cmd_line}`: {e}\n\n{stdout}");
+        Failure::new(FailureKind::CommandFailed, message)
+            .with_command(cmd_line, Some(&output.stderr))
+    })?)
+}
+
+const TIMESTAMP_OVERRIDE_VAR: &str = "FURIOSA_BUILD_TIMESTAMP_OVERRIDE";
+const DETERMINISTIC_VAR: &str = "FURIOSA_METADATA_DETERMINISTIC";
+
+/// Whether the `deterministic` feature is enabled.
+const DETERMINISTIC: bool = cfg!(feature = "deterministic");
+
+/// Returns the date and time of the current build, and where it came from.
+fn build_timestamp(
+    details: Option<&GitDetails>,
+) -> Result<(DateTime<Utc>, Source), Box<dyn std::error::Error>> {
+    for var in [TIMESTAMP_OVERRIDE_VAR, "SOURCE_DATE_EPOCH", DETERMINISTIC_VAR, CLOCK_SKEW_VAR] {
+        println!("cargo:rerun-if-env-changed={var}");
+    }
+    let (timestamp, source) = resolve_build_timestamp(
+        non_empty_var(TIMESTAMP_OVERRIDE_VAR)?.as_deref(),
+        non_empty_var("SOURCE_DATE_EPOCH")?.as_deref(),
+        DETERMINISTIC || matches!(env::var(DETERMINISTIC_VAR).as_deref(), Ok("1")),
+        || head_commit_time(details),
+    )?;
+
+    let substitute = parse_clock_skew(non_empty_var(CLOCK_SKEW_VAR)?.as_deref())?;
+    // only the clock can be wrong by accident, and the commit date is the only reference
+    let (Source::Clock, Some(details)) = (source, details) else {
+        return Ok((timestamp, source));
+    };
+    let Some(skew) = clock_skew(timestamp, details.commit_time) else {
+        return Ok((timestamp, source));
+    };
+    if substitute {
+        eprintln!("[furiosa-metadata] The system clock {skew}, using the commit date instead.");
+        return Ok((details.commit_time, Source::CommitDate));
+    }
+    println!(
+        "cargo:warning=The system clock {skew}, so the build timestamp is likely wrong. \
+         Set {CLOCK_SKEW_VAR}=commit-date to use the commit date instead."
+    );
+    Ok((timestamp, source))
+}
+
+const CLOCK_SKEW_VAR: &str = "FURIOSA_METADATA_CLOCK_SKEW";
+
+/// Parses `FURIOSA_METADATA_CLOCK_SKEW`, returning true to substitute the commit date.
+fn parse_clock_skew(value: Option<&str>) -> Result<bool, String> {
+    match value {
+        None | Some("warn") => Ok(false),
+        Some("commit-date") => Ok(true),
+        Some(value) => Err(format!(
+            "{CLOCK_SKEW_VAR} contains an invalid value {value:?} \
+             (expected `warn` or `commit-date`)"
+        )),
+    }
+}
+
+/// Describes how the clock is obviously wrong, i.e. more than a day before the commit date
+/// (commit dates may be slightly ahead of other clocks) or more than 10 years after it.
+fn clock_skew(now: DateTime<Utc>, commit_time: DateTime<Utc>) -> Option<String> {
+    let (now_str, commit_str) = (format_timestamp(&now), format_timestamp(&commit_time));
+    if now < commit_time - chrono::Duration::days(1) {
+        Some(format!("reads {now_str}, before the commit date {commit_str}"))
+    } else if now > commit_time + chrono::Duration::days(3653) {
+        Some(format!("reads {now_str}, more than 10 years after the commit date {commit_str}"))
+    } else {
+        None
+    }
+}
+
+/// Resolves the build timestamp in the documented order of precedence.
+fn resolve_build_timestamp(
+    timestamp_override: Option<&str>,
+    source_date_epoch: Option<&str>,
+    deterministic: bool,
+    commit_time: impl FnOnce() -> Result<DateTime<Utc>, Box<dyn std::error::Error>>,
+) -> Result<(DateTime<Utc>, Source), Box<dyn std::error::Error>> {
+    if let Some(timestamp) = timestamp_override {
+        let timestamp = parse_timestamp(timestamp).map_err(|e| {
+            format!(
+                "{TIMESTAMP_OVERRIDE_VAR} contains an invalid timestamp {timestamp:?}: {e} \
+                 (expected ISO 8601 with a time zone, e.g. `2025-01-07T10:00:00Z` \
+                 or `20250107T100000Z`)"
+            )
+        })?;
+        return Ok((timestamp, Source::Env));
+    }
+
+    if let Some(epoch) = source_date_epoch {
+        let timestamp =
+            epoch.parse().ok().and_then(|secs| Utc.timestamp_opt(secs, 0).single()).ok_or_else(
+                || format!("SOURCE_DATE_EPOCH contains an invalid timestamp {epoch:?}"),
+            )?;
+        return Ok((timestamp, Source::SourceDateEpoch));
+    }
+
+    if deterministic {
+        return Ok((commit_time()?, Source::CommitDate));
+    }
+
+    Ok((Utc::now(), Source::Clock))
+}
+
+/// Parses an ISO 8601 timestamp with a time zone, either in the extended format
+/// (RFC 3339, e.g. `2025-01-07T19:00:00+09:00`) or the basic format (e.g. `20250107T190000+0900`).
+fn parse_timestamp(timestamp: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+    let extended = basic_to_extended_format(timestamp);
+    let timestamp = DateTime::parse_from_rfc3339(extended.as_deref().unwrap_or(timestamp))?;
+    Ok(timestamp.with_timezone(&Utc))
+}
+
+fn basic_to_extended_format(timestamp: &str) -> Option<String> {
+    let is_digits = |s: &str| s.bytes().all(|c| c.is_ascii_digit());
+    let (date, time) = timestamp.split_once('T')?;
+    let (time, zone) = (time.get(..6)?, time.get(6..)?);
+    if date.len() != 8 || !is_digits(date) || !is_digits(time) {
+        return None;
+    }
+    let zone = match zone.as_bytes() {
+        b"Z" => "Z".to_owned(),
+        [b'+' | b'-', ..] if zone.len() == 5 && is_digits(&zone[1..]) => {
+            format!("{}:{}", &zone[..3], &zone[3..])
+        }
+        _ => return None,
+    };
+    Some(format!(
+        "{}-{}-{}T{}:{}:{}{zone}",
+        &date[..4],
+        &date[4..6],
+        &date[6..],
+        &time[..2],
+        &time[2..4],
+        &time[4..],
+    ))
+}
+
+/// Returns the committer date of HEAD.
+/// Returns the committer date of HEAD, which is only known if the hash is from git.
+fn head_commit_time(
+    details: Option<&GitDetails>,
+) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+    let details = details.ok_or_else(|| {
+        format!(
+            "The commit date is not known for the deterministic build timestamp, \
+             as the hash is not from git (see FURIOSA_METADATA_SOURCE). \
+             Set SOURCE_DATE_EPOCH or {TIMESTAMP_OVERRIDE_VAR} instead."
+        )
+    })?;
+    Ok(details.commit_time)
+}
 
-    Ok(parse(stdout)
-        .map_err(|e| format!("Unexpected output from `{cmd_line}`: {e}\n\n{stdout}"))?)
+/// Returns the value of given environment variable, treating an empty value as unset.
+fn non_empty_var(name: &str) -> Result<Option<String>, VarError> {
+    match env::var(name) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn format_timestamp(timestamp: &DateTime<Utc>) -> String {
+    timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string()
 }
 
-/// Returns the date and time of the current build.
-fn build_timestamp() -> String {
-    Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+/// Describes the compilation caches that were active for the current build,
+/// e.g. `rustc-wrapper=sccache;incremental=unset`.
+///
+/// Cargo passes the effective `RUSTC_WRAPPER` to build scripts, so a wrapper configured via
+/// `.cargo/config.toml` is detected as well. Incremental compilation is decided by the profile,
+/// which is not visible to build scripts, so only an explicit `CARGO_INCREMENTAL` is recorded.
+fn build_cache() -> String {
+    println!("cargo:rerun-if-env-changed=RUSTC_WRAPPER");
+    println!("cargo:rerun-if-env-changed=CARGO_INCREMENTAL");
+
+    let wrapper = env::var_os("RUSTC_WRAPPER")
+        .filter(|wrapper| !wrapper.is_empty())
+        .map(|wrapper| match Path::new(&wrapper).file_stem() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => wrapper.to_string_lossy().into_owned(),
+        })
+        .unwrap_or_else(|| "none".to_owned());
+    let incremental = env::var("CARGO_INCREMENTAL").unwrap_or_else(|_| "unset".to_owned());
+
+    format!("rustc-wrapper={wrapper};incremental={incremental}")
 }
 
 #[test]
 fn tests() -> Result<(), Box<dyn std::error::Error>> {
-    assert!(!git_short_hash(&[])?.is_empty());
+    let workspace_dir = get_workspace_dir()?;
+    assert!(!git_head_short_hash(&workspace_dir)?.is_empty());
+    git_dirty(&workspace_dir, &[])?;
+    let git_dir = discover_git_dir()?.ok_or("no repository")?;
+    assert_eq!(git_dir.head_commit()?, Some(git_details(&workspace_dir)?.hash));
+    assert!(git_dir.watched_paths()?.contains(&git_dir.git_dir.join("HEAD")));
+    Ok(())
+}
+
+#[test]
+fn list_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let components = ["rt\nfirmware", "docs;old", "C:\\tools"];
+    let mut vars = vec![
+        ("FURIOSA_CHANGED_COMPONENTS".to_owned(), list::format_list(&components)),
+        ("FURIOSA_GIT_BRANCH".to_owned(), "main\ncargo:rustc-cfg=injected".to_owned()),
+    ];
+    encode_values(&mut vars, 4096)?;
+    let stamp = format_stamp(&vars);
+    let parsed = parse_stamp(&stamp)?;
+
+    assert_eq!(stamp.lines().count(), 3);
+    assert_eq!(list::parse_list(&parsed["FURIOSA_CHANGED_COMPONENTS"])?, components);
+    assert_eq!(parsed["FURIOSA_GIT_BRANCH"], "main\\ncargo:rustc-cfg=injected");
+    assert_eq!(parsed["FURIOSA_METADATA_TRUNCATED"], "");
+    Ok(())
+}
+
+#[test]
+fn stamp_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let vars = vec![
+        ("FURIOSA_GIT_SHORT_HASH".to_owned(), "0123456789-modified".to_owned()),
+        ("FURIOSA_BUILD_CACHE".to_owned(), "rustc-wrapper=none;incremental=unset".to_owned()),
+    ];
+    let stamp = parse_stamp(&format_stamp(&vars))?;
+    assert_eq!(stamp.len(), 2);
+    assert_eq!(stamp["FURIOSA_BUILD_CACHE"], "rustc-wrapper=none;incremental=unset");
+    assert!(parse_stamp("FURIOSA_GIT_SHORT_HASH\n").is_err());
+    Ok(())
+}
+
+#[test]
+fn release_channels() {
+    assert_eq!(default_release_channel(""), "release");
+    assert_eq!(default_release_channel("nightly.20250107"), "nightly");
+    assert_eq!(default_release_channel("RC1"), "rc");
+    assert_eq!(default_release_channel("1"), "prerelease");
+}
+
+#[test]
+fn rustc_versions() {
+    assert_eq!(parse_rustc_version("rustc 1.80.1 (3f5fd8dd4 2024-08-06)"), Some((1, 80)));
+    assert_eq!(parse_rustc_version("rustc 1.69.0-nightly (dc1d9d50f 2023-01-31)"), Some((1, 69)));
+    assert_eq!(parse_rustc_version("clippy 0.1.69"), None);
+}
+
+#[test]
+fn build_timestamps() -> Result<(), Box<dyn std::error::Error>> {
+    let commit_time = || Ok(Utc.timestamp_opt(1736244000, 0).unwrap());
+    let resolve = |timestamp_override, source_date_epoch, deterministic| {
+        resolve_build_timestamp(timestamp_override, source_date_epoch, deterministic, commit_time)
+            .map(|(timestamp, source)| (format_timestamp(&timestamp), source))
+    };
+
+    assert_eq!(
+        resolve(Some("2025-01-07T19:00:00+09:00"), Some("0"), true)?,
+        ("2025-01-07T10:00:00Z".to_owned(), Source::Env)
+    );
+    assert_eq!(
+        resolve(None, Some("1736244000"), true)?,
+        ("2025-01-07T10:00:00Z".to_owned(), Source::SourceDateEpoch)
+    );
+    assert_eq!(resolve(None, None, true)?, ("2025-01-07T10:00:00Z".to_owned(), Source::CommitDate));
+    assert_eq!(resolve(None, None, false)?.1, Source::Clock);
+
+    assert_eq!(resolve(Some("20250107T100000Z"), None, false)?.0, "2025-01-07T10:00:00Z");
+    assert_eq!(resolve(Some("20250107T190000+0900"), None, false)?.0, "2025-01-07T10:00:00Z");
+    assert!(resolve(Some("2025-01-07 10:00"), None, false).is_err());
+    assert!(resolve(Some("20250107T100000"), None, false).is_err());
+    assert!(resolve(Some("20250107T1000Z"), None, false).is_err());
+    assert!(resolve(None, Some("yesterday"), false).is_err());
+
+    // e.g. an injected hash or one from `.cargo_vcs_info.json`, without the commit date
+    let without_git = |source_date_epoch| {
+        resolve_build_timestamp(None, source_date_epoch, true, || head_commit_time(None))
+    };
+    assert!(without_git(None).unwrap_err().to_string().contains("Set SOURCE_DATE_EPOCH"));
+    assert_eq!(without_git(Some("1736244000"))?.1, Source::SourceDateEpoch);
+
+    let commit_time = commit_time()?;
+    assert!(clock_skew(commit_time, commit_time).is_none());
+    assert!(clock_skew(commit_time - chrono::Duration::hours(1), commit_time).is_none());
+    assert!(clock_skew(Utc.timestamp_opt(0, 0).unwrap(), commit_time)
+        .map_or(false, |skew| skew.contains("before the commit date")));
+    assert!(clock_skew(commit_time + chrono::Duration::days(4000), commit_time).is_some());
+    Ok(())
+}
+
+#[test]
+fn fallback_hashes() -> Result<(), String> {
+    let hash = parse_vcs_info(
+        r#"{"git": {"sha1": "0123456789abcdef0123456789abcdef01234567", "dirty": true}, "path_in_vcs": ""}"#,
+    )?;
+    assert_eq!(hash.short_hash, "012345678-modified");
+    assert_eq!(hash.full_hash.as_deref(), Some("0123456789abcdef0123456789abcdef01234567"));
+    assert_eq!(hash.dirty, Some(true));
+    assert!(parse_vcs_info(r#"{"path_in_vcs": ""}"#).is_err());
+
+    let hash = parse_hash_file("0123456789-modified\n")?;
+    assert_eq!((hash.short_hash.as_str(), hash.dirty), ("0123456789-modified", Some(true)));
+    assert!(parse_hash_file("unknown").is_err());
+    Ok(())
+}
+
+#[test]
+fn vendored_packages() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = env::temp_dir().join(format!("furiosa-metadata-vendored-{}", std::process::id()));
+    let package = dir.join("vendor/foo");
+    fs::create_dir_all(dir.join(".git/refs/heads"))?;
+    fs::create_dir_all(&package)?;
+    fs::write(dir.join(".git/HEAD"), "ref: refs/heads/main\n")?;
+    fs::write(dir.join(".git/refs/heads/main"), "89abcdef0123456789abcdef0123456789abcdef\n")?;
+    fs::write(
+        package.join(VCS_INFO_FILE_NAME),
+        r#"{"git": {"sha1": "0123456789abcdef0123456789abcdef01234567"}, "path_in_vcs": ""}"#,
+    )?;
+
+    let monorepo_head = GitDir::discover(&package, &[])?.ok_or("no repository")?.head_commit()?;
+    let hash = packaged_hash(&package)?;
+    let not_packaged = packaged_hash(&dir)?;
+    fs::remove_dir_all(&dir)?;
+
+    // the package is in the monorepo, but its hash is the one it was created from
+    assert_eq!(monorepo_head.as_deref(), Some("89abcdef0123456789abcdef0123456789abcdef"));
+    let hash = hash.ok_or("not packaged")?;
+    assert_eq!(hash.short_hash, "012345678");
+    assert_eq!((hash.hash_source, hash.dirty), (Source::VcsInfo, Some(false)));
+    assert!(not_packaged.is_none());
     Ok(())
 }
+
+#[test]
+fn builder_fingerprints() {
+    let identity = ("build-01".to_owned(), "ci".to_owned());
+    let fingerprint = |salt, identity| builder_fingerprint(HashAlgorithm::Sha256, salt, identity);
+    assert!(fingerprint("salt", &identity).starts_with("sha256:"));
+    assert_eq!(fingerprint("salt", &identity).len(), 23);
+    assert_eq!(fingerprint("salt", &identity), fingerprint("salt", &identity));
+    assert_ne!(fingerprint("salt", &identity), fingerprint("pepper", &identity));
+    assert_ne!(
+        fingerprint("salt", &identity),
+        fingerprint("salt", &("build-0".to_owned(), "1ci".to_owned()))
+    );
+    #[cfg(feature = "blake3")]
+    assert!(builder_fingerprint(HashAlgorithm::Blake3, "salt", &identity).starts_with("blake3:"));
+}
+
+#[test]
+fn const_assertions() {
+    assert!(__matches("release/*", "release/1.2"));
+    assert!(__matches("release/*", "release/"));
+    assert!(__matches("*-rc?", "feature/x-rc1"));
+    assert!(__matches("main", "main"));
+    assert!(!__matches("release/*", "main"));
+    assert!(!__matches("main", "main2"));
+    assert!(!__matches("release/*", "unknown"));
+
+    let version = "rustc 1.80.1 (3f5fd8dd4 2024-08-06)";
+    assert!(__rustc_at_least(version, "1.75"));
+    assert!(__rustc_at_least(version, "1.80.1"));
+    assert!(!__rustc_at_least(version, "1.80.2"));
+    assert!(!__rustc_at_least(version, "2"));
+    assert!(__rustc_at_least("rustc 1.81.0-nightly (ba1d7f4a0 2024-06-29)", "1.80"));
+    assert!(!__rustc_at_least("unknown", "1.0"));
+}
+
+#[cfg(test)]
+#[no_mangle]
+extern "C" fn furiosa_metadata_test_symbol() -> u32 {
+    42
+}
+
+// the test binary must still link without the cfg set by the build script
+#[cfg(test)]
+symbol_versions!(furiosa_metadata_test_symbol);
+
+#[test]
+fn symbol_versions() {
+    assert_eq!(furiosa_metadata_test_symbol(), 42);
+    assert_eq!(symbol_version_node("FURIOSA_RT", "1.2.3"), "FURIOSA_RT_1.2.3");
+    assert_eq!(
+        symbol_version_node("FURIOSA_RT", "1.2.0-nightly.1+linux"),
+        "FURIOSA_RT_1.2.0_nightly.1_linux"
+    );
+
+    let gnu = "x86_64-unknown-linux-gnu";
+    assert!(!links_with_lld(None, "", gnu, Some((1, 69))));
+    assert!(links_with_lld(None, "", gnu, Some((1, 90))));
+    assert!(!links_with_lld(None, "-C\x1flinker-features=-lld", gnu, Some((1, 90))));
+    assert!(links_with_lld(None, "-C\x1flink-arg=-fuse-ld=lld", gnu, Some((1, 69))));
+    assert!(links_with_lld(None, "-Zgcc-ld=lld", gnu, Some((1, 69))));
+    assert!(links_with_lld(Some("/usr/bin/ld.lld"), "", "aarch64-unknown-linux-gnu", None));
+    assert!(!links_with_lld(Some("/usr/bin/cc"), "", "aarch64-unknown-linux-gnu", None));
+}
+
+#[test]
+fn escaped_values() {
+    assert_eq!(escape_value("main\ncargo:rustc-cfg=injected"), "main\\ncargo:rustc-cfg=injected");
+    assert_eq!(escape_value("a\r\t\u{7f}b"), "a\\r\\t\\u{7f}b");
+    assert_eq!(escape_value("v1.0.0-1-g0123456=x\\y"), "v1.0.0-1-g0123456=x\\y");
+    assert!(is_valid_name("FURIOSA_GIT_HASH"));
+    assert!(!is_valid_name("FURIOSA_GIT=HASH"));
+    assert!(!is_valid_name(""));
+}
+
+#[test]
+fn truncated_values() {
+    let mut value = "0123456789".to_owned();
+    assert!(!truncate_value(&mut value, 14));
+    assert_eq!(value, "0123456789");
+
+    // never cuts in the middle of a character
+    let mut value = "v1.0.0-\u{ac00}\u{ac01}-long-description".to_owned();
+    assert!(truncate_value(&mut value, 23));
+    assert_eq!(value, "v1.0.0-...[truncated]");
+    let mut value = "v1.0.0-\u{ac00}\u{ac01}-long-description".to_owned();
+    assert!(truncate_value(&mut value, 24));
+    assert_eq!(value, "v1.0.0-\u{ac00}...[truncated]");
+}
+
+#[test]
+fn jobservers() {
+    let windows = "-j --jobserver-fds=__rust_jobserver_semaphore_3740925394 \
+                   --jobserver-auth=__rust_jobserver_semaphore_3740925394";
+    assert_eq!(jobserver_auth(windows), Some("__rust_jobserver_semaphore_3740925394"));
+    assert_eq!(
+        jobserver_auth("-j --jobserver-auth=fifo:/tmp/jobserver"),
+        Some("fifo:/tmp/jobserver")
+    );
+    assert_eq!(jobserver_auth("-j"), None);
+}
@@ -0,0 +1,148 @@
+//! The encoding of list values stamped by this crate, i.e. `FURIOSA_BUILD_FEATURES`,
+//! `FURIOSA_CHANGED_COMPONENTS` and `FURIOSA_METADATA_TRUNCATED`, for tools parsing them.
+//!
+//! Items are separated by `;`, and `;` and `\` in an item are escaped with `\`,
+//! e.g. `["a;b", "c\d", "e"]` is encoded as `a\;b;c\\d;e`. Control characters are escaped as in
+//! Rust (e.g. `\n`, `\t` or `\u{7f}`), so that the value fits in a line. Splitting on `;` is
+//! wrong for items containing it, so use [`parse_list`] instead.
+//!
+//! An empty value is an empty list, so a list of a single empty item can't be represented,
+//! and is decoded as an empty list.
+
+/// The separator between items.
+pub const SEPARATOR: char = ';';
+
+/// The escape character, which precedes a literal [`SEPARATOR`], itself or an escaped control
+/// character.
+pub const ESCAPE: char = '\\';
+
+/// Encodes `items` into a list value.
+pub fn format_list<S: AsRef<str>>(items: &[S]) -> String {
+    let mut list = String::new();
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            list.push(SEPARATOR);
+        }
+        for c in item.as_ref().chars() {
+            if c == SEPARATOR || c == ESCAPE {
+                list.push(ESCAPE);
+                list.push(c);
+            } else if c.is_control() {
+                list.extend(c.escape_default());
+            } else {
+                list.push(c);
+            }
+        }
+    }
+    list
+}
+
+/// Truncates the list value `list` to `max_len` bytes between items, ending with an item `marker`.
+/// Returns true if truncated.
+///
+/// Cutting a list value anywhere else could leave a dangling [`ESCAPE`], which can't be parsed.
+/// `max_len` must be at least the length of `marker`.
+pub(crate) fn truncate_list(list: &mut String, max_len: usize, marker: &str) -> bool {
+    if list.len() <= max_len {
+        return false;
+    }
+    let mut escaped = false;
+    let mut end = 0;
+    for (i, c) in list.char_indices() {
+        if i + SEPARATOR.len_utf8() + marker.len() > max_len {
+            break;
+        }
+        match c {
+            _ if escaped => escaped = false,
+            ESCAPE => escaped = true,
+            SEPARATOR => end = i + SEPARATOR.len_utf8(),
+            _ => {}
+        }
+    }
+    list.truncate(end);
+    list.push_str(marker);
+    true
+}
+
+/// Decodes a list value into its items.
+///
+/// Fails on an escape character not followed by [`SEPARATOR`], itself or an escaped control
+/// character.
+pub fn parse_list(list: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    if list.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut items = vec![String::new()];
+    let mut chars = list.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ESCAPE => {
+                let c = match chars.next() {
+                    Some(c @ (SEPARATOR | ESCAPE)) => c,
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => parse_unicode_escape(&mut chars)
+                        .ok_or_else(|| format!("Invalid escape {ESCAPE}u in {list:?}"))?,
+                    Some(c) => return Err(format!("Invalid escape {ESCAPE}{c} in {list:?}").into()),
+                    None => return Err(format!("Unterminated escape in {list:?}").into()),
+                };
+                items.last_mut().unwrap().push(c);
+            }
+            SEPARATOR => items.push(String::new()),
+            c => items.last_mut().unwrap().push(c),
+        }
+    }
+    Ok(items)
+}
+
+/// Parses the rest of `\u{7f}` after `\u`.
+fn parse_unicode_escape(chars: &mut std::str::Chars<'_>) -> Option<char> {
+    let rest = chars.as_str().strip_prefix('{')?;
+    let (hex, _) = rest.split_once('}')?;
+    let c = char::from_u32(u32::from_str_radix(hex, 16).ok()?)?;
+    // `{`, the digits and `}`
+    chars.nth(hex.len() + 1);
+    Some(c)
+}
+
+#[test]
+fn lists() -> Result<(), Box<dyn std::error::Error>> {
+    for items in [
+        &["default", "foo_bar"][..],
+        &["a;b", "c\\d", "e"],
+        &["", "", "trailing\\"],
+        &["\\;", ";;"],
+        &["line\nbreak", "\u{7f}\t\r", "\\n"],
+        &[],
+    ] {
+        assert_eq!(parse_list(&format_list(items))?, items);
+    }
+    assert_eq!(format_list(&["a;b", "c\\d", "e"]), "a\\;b;c\\\\d;e");
+    assert_eq!(parse_list("GIT_DESCRIBE;FIRMWARE_VERSION")?, ["GIT_DESCRIBE", "FIRMWARE_VERSION"]);
+    assert_eq!(parse_list("")?, Vec::<String>::new());
+    assert!(parse_list("a\\b").is_err());
+    assert!(parse_list("a\\").is_err());
+    assert_eq!(format_list(&["a\nb", "\u{1b}"]), "a\\nb;\\u{1b}");
+    assert!(parse_list("a\\u{110000}").is_err());
+    assert!(parse_list("a\\u{7f").is_err());
+
+    // never leaves a dangling escape
+    let list = format_list(&["ab", "c;d", "ef"]);
+    for (max_len, expected) in [
+        (1, &["!"][..]),
+        (3, &["!"]),
+        (4, &["ab", "!"]),
+        (8, &["ab", "!"]),
+        (9, &["ab", "c;d", "!"]),
+    ] {
+        let mut truncated = list.clone();
+        assert!(truncate_list(&mut truncated, max_len, "!"));
+        assert!(truncated.len() <= max_len);
+        assert_eq!(parse_list(&truncated)?, expected);
+    }
+    let mut untouched = list.clone();
+    assert!(!truncate_list(&mut untouched, list.len(), "!"));
+    assert_eq!(untouched, list);
+    Ok(())
+}
@@ -0,0 +1,369 @@
+//! The build metadata as a single value, for the runtime use.
+
+use std::fmt;
+
+use crate::hash;
+
+/// The build metadata of a crate, usually created by [`build_metadata!`](crate::build_metadata).
+///
+/// With the `serde` feature, this implements `serde::Serialize` so that it can be directly
+/// returned from e.g. an HTTP `/version` endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct BuildMetadata {
+    pub version: &'static str,
+    pub git_short_hash: &'static str,
+    pub git_hash: &'static str,
+    pub git_branch: &'static str,
+    pub git_describe: &'static str,
+    pub git_commit_date: &'static str,
+    /// `None` if not known, e.g. the hash was injected.
+    pub git_dirty: Option<bool>,
+    pub build_timestamp: &'static str,
+    pub channel: &'static str,
+}
+
+impl BuildMetadata {
+    #[doc(hidden)]
+    #[allow(clippy::too_many_arguments)]
+    pub const fn __new(
+        version: &'static str,
+        git_short_hash: &'static str,
+        git_hash: &'static str,
+        git_branch: &'static str,
+        git_describe: &'static str,
+        git_commit_date: &'static str,
+        git_dirty: &'static str,
+        build_timestamp: &'static str,
+        channel: &'static str,
+    ) -> Self {
+        BuildMetadata {
+            version,
+            git_short_hash,
+            git_hash,
+            git_branch,
+            git_describe,
+            git_commit_date,
+            git_dirty: crate::__parse_bool(git_dirty),
+            build_timestamp,
+            channel,
+        }
+    }
+
+    /// Compares with the metadata of a peer, e.g. during the connection setup between
+    /// distributed components, which should reject [`Compatibility::Skewed`] peers and
+    /// warn about [`Compatibility::Dirty`] ones.
+    ///
+    /// The peer is either another [`BuildMetadata`], or a [`PeerMetadata`] received at runtime.
+    pub fn compatibility_with<'a>(&self, other: impl Into<Peer<'a>>) -> Compatibility {
+        let (this, other) = (Peer::from(self), other.into());
+        if this.version != other.version {
+            return Compatibility::Skewed;
+        }
+        if this.is_dirty() || other.is_dirty() {
+            return Compatibility::Dirty;
+        }
+        match (this.commit(), other.commit()) {
+            // either may only have the short hash, e.g. if injected
+            (Some(a), Some(b)) if a.starts_with(b) || b.starts_with(a) => Compatibility::Identical,
+            _ => Compatibility::Compatible,
+        }
+    }
+
+    /// The name and the visibility of every field, so that the privacy review of what may be
+    /// reported is done once here. A new field must be added here and classified.
+    pub const FIELDS: &'static [(&'static str, Visibility)] = &[
+        ("version", Visibility::Public),
+        ("git_short_hash", Visibility::Public),
+        // the full hash adds nothing to the short one for support
+        ("git_hash", Visibility::Internal),
+        // branch names and tags often mention unreleased features or customers
+        ("git_branch", Visibility::Internal),
+        ("git_describe", Visibility::Internal),
+        ("git_commit_date", Visibility::Internal),
+        ("git_dirty", Visibility::Public),
+        ("build_timestamp", Visibility::Public),
+        ("channel", Visibility::Public),
+    ];
+
+    /// Returns only the [`Visibility::Public`] fields, e.g. for crash and usage reports
+    /// sent outside.
+    pub fn public_view(&self) -> PublicMetadata {
+        // exhaustive, so that a new field fails to compile until it is classified here
+        let BuildMetadata {
+            version,
+            git_short_hash,
+            git_hash: _,
+            git_branch: _,
+            git_describe: _,
+            git_commit_date: _,
+            git_dirty,
+            build_timestamp,
+            channel,
+        } = *self;
+        PublicMetadata { version, git_short_hash, git_dirty, build_timestamp, channel }
+    }
+}
+
+/// Whether a field of [`BuildMetadata`] may leave the organization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Visibility {
+    /// Safe to send outside, e.g. in crash and usage reports.
+    Public,
+    /// Only for internal use, e.g. as it may reveal internal names.
+    Internal,
+}
+
+/// The [`Visibility::Public`] subset of [`BuildMetadata`], created by
+/// [`BuildMetadata::public_view`].
+///
+/// With the `serde` feature, this implements `serde::Serialize` like [`BuildMetadata`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct PublicMetadata {
+    pub version: &'static str,
+    pub git_short_hash: &'static str,
+    /// `None` if not known, e.g. the hash was injected.
+    pub git_dirty: Option<bool>,
+    pub build_timestamp: &'static str,
+    pub channel: &'static str,
+}
+
+/// The build metadata of a peer received at runtime, e.g. in a handshake, to be compared with
+/// [`BuildMetadata::compatibility_with`].
+///
+/// With the `serde` feature, this implements `serde::Deserialize`, which accepts a serialized
+/// [`BuildMetadata`] as well. Missing fields are empty, i.e. unknown.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PeerMetadata {
+    pub version: String,
+    pub git_short_hash: String,
+    /// The full hash, or empty if not known.
+    pub git_hash: String,
+    /// `None` if not known, e.g. the hash was injected.
+    pub git_dirty: Option<bool>,
+}
+
+impl From<&BuildMetadata> for PeerMetadata {
+    fn from(metadata: &BuildMetadata) -> Self {
+        PeerMetadata {
+            version: metadata.version.to_owned(),
+            git_short_hash: metadata.git_short_hash.to_owned(),
+            git_hash: metadata.git_hash.to_owned(),
+            git_dirty: metadata.git_dirty,
+        }
+    }
+}
+
+/// The fields compared by [`BuildMetadata::compatibility_with`], borrowed from either
+/// a [`BuildMetadata`] or a [`PeerMetadata`].
+#[derive(Debug, Clone, Copy)]
+pub struct Peer<'a> {
+    version: &'a str,
+    git_short_hash: &'a str,
+    git_hash: &'a str,
+    git_dirty: Option<bool>,
+}
+
+impl Peer<'_> {
+    /// Returns true if built from a modified working tree.
+    /// The status is unknown for an injected hash, which then counts as clean unless marked.
+    fn is_dirty(&self) -> bool {
+        self.git_dirty == Some(true) || hash::split_dirty_suffix(self.git_short_hash).1
+    }
+
+    /// Returns the full hash if known, or the short hash otherwise, if not a placeholder.
+    fn commit(&self) -> Option<&str> {
+        if hash::is_full_hash(self.git_hash) {
+            return Some(self.git_hash);
+        }
+        hash::parse_git_hash(self.git_short_hash).ok().map(|(hash, _)| hash)
+    }
+}
+
+impl<'a> From<&'a BuildMetadata> for Peer<'a> {
+    fn from(metadata: &'a BuildMetadata) -> Self {
+        let BuildMetadata { version, git_short_hash, git_hash, git_dirty, .. } = *metadata;
+        Peer { version, git_short_hash, git_hash, git_dirty }
+    }
+}
+
+impl<'a> From<&'a PeerMetadata> for Peer<'a> {
+    fn from(metadata: &'a PeerMetadata) -> Self {
+        Peer {
+            version: &metadata.version,
+            git_short_hash: &metadata.git_short_hash,
+            git_hash: &metadata.git_hash,
+            git_dirty: metadata.git_dirty,
+        }
+    }
+}
+
+/// The result of [`BuildMetadata::compatibility_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Compatibility {
+    /// The same version built from the same commit.
+    Identical,
+    /// The same version built from different (or unknown) commits.
+    Compatible,
+    /// The same version, but either is built from a modified working tree,
+    /// so they may behave differently.
+    Dirty,
+    /// Different versions.
+    Skewed,
+}
+
+impl Compatibility {
+    /// Returns true unless [`Compatibility::Skewed`]. A [`Compatibility::Dirty`] peer is accepted,
+    /// but should be warned about.
+    pub fn is_accepted(self) -> bool {
+        self != Compatibility::Skewed
+    }
+}
+
+/// Formats the metadata in a single line, e.g. `1.2.3 (0123456789-modified 2025-01-07T10:00:00Z)`.
+impl fmt::Display for BuildMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} {})", self.version, self.git_short_hash, self.build_timestamp)
+    }
+}
+
+/// Formats the metadata in the same way as [`BuildMetadata`].
+impl fmt::Display for PublicMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} {})", self.version, self.git_short_hash, self.build_timestamp)
+    }
+}
+
+/// Creates a [`BuildMetadata`](crate::BuildMetadata) for the current crate.
+///
+/// This requires [`set_metadata_env_vars`](crate::set_metadata_env_vars) in the build script,
+/// and can be used in a constant context:
+///
+/// ```ignore
+/// pub const BUILD_METADATA: furiosa_metadata::BuildMetadata = furiosa_metadata::build_metadata!();
+/// ```
+#[macro_export]
+macro_rules! build_metadata {
+    () => {
+        $crate::BuildMetadata::__new(
+            env!("CARGO_PKG_VERSION"),
+            env!("FURIOSA_GIT_SHORT_HASH"),
+            env!("FURIOSA_GIT_HASH"),
+            env!("FURIOSA_GIT_BRANCH"),
+            env!("FURIOSA_GIT_DESCRIBE"),
+            env!("FURIOSA_GIT_COMMIT_DATE"),
+            env!("FURIOSA_GIT_DIRTY"),
+            env!("FURIOSA_BUILD_TIMESTAMP"),
+            env!("FURIOSA_CHANNEL"),
+        )
+    };
+}
+
+#[test]
+fn display() {
+    const METADATA: BuildMetadata = BuildMetadata::__new(
+        "1.2.3",
+        "0123456789-modified",
+        "0123456789abcdef0123456789abcdef01234567",
+        "main",
+        "v1.2.3-4-g0123456789",
+        "2025-01-07T09:00:00Z",
+        "true",
+        "2025-01-07T10:00:00Z",
+        "release",
+    );
+    assert_eq!(METADATA.git_dirty, Some(true));
+    assert_eq!(METADATA.to_string(), "1.2.3 (0123456789-modified 2025-01-07T10:00:00Z)");
+}
+
+#[test]
+fn compatibility() {
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+    let metadata = |version, git_short_hash, git_hash, git_dirty| {
+        BuildMetadata::__new(
+            version,
+            git_short_hash,
+            git_hash,
+            "main",
+            "v1.2.3",
+            "2025-01-07T09:00:00Z",
+            git_dirty,
+            "2025-01-07T10:00:00Z",
+            "release",
+        )
+    };
+    let clean = metadata("1.2.3", "0123456789", HASH, "false");
+    let injected = metadata("1.2.3", "0123456789", "unknown", "unknown");
+    let other = metadata("1.2.3", "fedcba9876", "unknown", "unknown");
+    let dirty = metadata("1.2.3", "0123456789-modified", HASH, "true");
+    let injected_dirty = metadata("1.2.3", "0123456789-modified", "unknown", "unknown");
+    let newer = metadata("1.2.4", "0123456789", HASH, "false");
+
+    assert_eq!(clean.compatibility_with(&clean), Compatibility::Identical);
+    assert_eq!(clean.compatibility_with(&injected), Compatibility::Identical);
+    assert_eq!(clean.compatibility_with(&other), Compatibility::Compatible);
+    assert_eq!(clean.compatibility_with(&dirty), Compatibility::Dirty);
+    assert_eq!(injected_dirty.compatibility_with(&injected), Compatibility::Dirty);
+    assert_eq!(clean.compatibility_with(&newer), Compatibility::Skewed);
+
+    // the peer only exists at runtime
+    let peer = PeerMetadata {
+        version: "1.2.3".to_owned(),
+        git_short_hash: "0123456789".to_owned(),
+        ..PeerMetadata::default()
+    };
+    assert_eq!(clean.compatibility_with(&peer), Compatibility::Identical);
+    let peer = PeerMetadata { git_dirty: Some(true), ..peer };
+    assert_eq!(clean.compatibility_with(&peer), Compatibility::Dirty);
+    let peer = PeerMetadata { version: "1.2.4".to_owned(), ..peer };
+    assert_eq!(clean.compatibility_with(&peer), Compatibility::Skewed);
+    assert_eq!(clean.compatibility_with(&PeerMetadata::from(&other)), Compatibility::Compatible);
+    assert!(Compatibility::Dirty.is_accepted());
+    assert!(!Compatibility::Skewed.is_accepted());
+}
+
+#[test]
+fn public_view() {
+    const METADATA: BuildMetadata = BuildMetadata::__new(
+        "1.2.3",
+        "0123456789",
+        "0123456789abcdef0123456789abcdef01234567",
+        "customer-x-hotfix",
+        "v1.2.3-4-g0123456789",
+        "2025-01-07T09:00:00Z",
+        "false",
+        "2025-01-07T10:00:00Z",
+        "release",
+    );
+    let public = METADATA.public_view();
+    assert_eq!(public.version, "1.2.3");
+    assert_eq!(public.git_short_hash, "0123456789");
+    assert_eq!(public.git_dirty, Some(false));
+    assert_eq!(public.channel, "release");
+    assert_eq!(public.to_string(), METADATA.to_string());
+
+    let public_fields: Vec<_> = BuildMetadata::FIELDS
+        .iter()
+        .filter(|(_, visibility)| *visibility == Visibility::Public)
+        .map(|(name, _)| *name)
+        .collect();
+    assert_eq!(
+        public_fields,
+        ["version", "git_short_hash", "git_dirty", "build_timestamp", "channel"]
+    );
+    assert!(!format!("{public:?}").contains("customer-x"));
+
+    // every field is classified, as the derived `Debug` lists them like `Serialize` does
+    let debug = format!("{METADATA:?}");
+    let fields: Vec<_> = debug
+        .split(", ")
+        .map(|field| field.trim_start_matches("BuildMetadata { ").split(':').next().unwrap())
+        .collect();
+    let names: Vec<_> = BuildMetadata::FIELDS.iter().map(|(name, _)| *name).collect();
+    assert_eq!(fields, names);
+}
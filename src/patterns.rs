@@ -0,0 +1,196 @@
+//! Patterns of updated paths that get special treatment in the dirty repository detection.
+
+use std::env::{self, VarError};
+
+use glob::Pattern;
+
+use crate::failure::{Failure, FailureKind};
+use crate::json;
+
+/// What to do with an updated path matching a pattern, in the increasing order of precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Policy {
+    /// Silently ignored.
+    Ignore,
+    /// Ignored with a note in the build script output.
+    Expect,
+    /// Ignored with a cargo warning.
+    Warn,
+    /// Fails the build.
+    Forbid,
+}
+
+impl Policy {
+    pub(crate) const ALL: [Policy; 4] =
+        [Policy::Ignore, Policy::Expect, Policy::Warn, Policy::Forbid];
+
+    /// Returns the name used in the pattern trace.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Policy::Ignore => "ignore",
+            Policy::Expect => "expect",
+            Policy::Warn => "warn",
+            Policy::Forbid => "forbid",
+        }
+    }
+
+    /// Returns the environment variable configuring the patterns with this policy.
+    pub(crate) fn var(self) -> &'static str {
+        match self {
+            Policy::Ignore => "FURIOSA_METADATA_IGNORE_MODIFIED",
+            Policy::Expect => "FURIOSA_METADATA_EXPECT_MODIFIED",
+            Policy::Warn => "FURIOSA_METADATA_WARN_MODIFIED",
+            Policy::Forbid => "FURIOSA_METADATA_FORBID_MODIFIED",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct PolicyPattern {
+    pub(crate) policy: Policy,
+    pub(crate) pattern: Pattern,
+}
+
+pub(crate) fn get_policy_patterns() -> Result<Vec<PolicyPattern>, Box<dyn std::error::Error>> {
+    for policy in Policy::ALL {
+        println!("cargo:rerun-if-env-changed={}", policy.var());
+    }
+    let patterns = read_policy_patterns()?;
+    for p in &patterns {
+        if let Some(suggestion) = lint_pattern(p.pattern.as_str()) {
+            println!(
+                "cargo:warning={} contains a suspicious pattern {:?}: {suggestion}",
+                p.policy.var(),
+                p.pattern.as_str()
+            );
+        }
+    }
+    Ok(patterns)
+}
+
+pub(crate) fn read_policy_patterns() -> Result<Vec<PolicyPattern>, Box<dyn std::error::Error>> {
+    let mut patterns = Vec::new();
+    for policy in Policy::ALL {
+        let var = policy.var();
+        match env::var(var) {
+            Ok(value) if value.is_empty() => {}
+            Ok(value) => {
+                for pattern in value.split(':') {
+                    if pattern.is_empty() {
+                        let message = format!("{var} contains an empty pattern");
+                        return Err(Failure::new(FailureKind::InvalidPattern, message).into());
+                    }
+                    let pattern = Pattern::new(pattern).map_err(|e| {
+                        let suggestion = lint_pattern(pattern).map(|s| format!("; {s}"));
+                        let message = format!(
+                            "{var} contains an invalid pattern {pattern:?}: {e}{}",
+                            suggestion.unwrap_or_default()
+                        );
+                        Failure::new(FailureKind::InvalidPattern, message)
+                    })?;
+                    patterns.push(PolicyPattern { policy, pattern });
+                }
+            }
+            Err(VarError::NotPresent) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(patterns)
+}
+
+/// Returns a suggestion for a pattern that looks like a common mistake, which either fails to
+/// parse or never matches what was probably meant.
+pub(crate) fn lint_pattern(pattern: &str) -> Option<String> {
+    if pattern.contains('\\') {
+        let fixed = pattern.replace('\\', "/");
+        return Some(format!("git reports paths with `/`, so try `{fixed}`"));
+    }
+    if let Some(fixed) = pattern.strip_prefix("./").or_else(|| pattern.strip_prefix('/')) {
+        return Some(format!("paths are relative to the repository root, so try `{fixed}`"));
+    }
+    if let Some(dir) = pattern.strip_suffix('/') {
+        return Some(format!(
+            "git reports files rather than directories, so try `{dir}/**` for everything in it"
+        ));
+    }
+    // `**` is only recursive as a whole path component
+    if let Some(i) = pattern.find("**").filter(|&i| {
+        let before = pattern[..i].chars().next_back();
+        let after = pattern[i + 2..].chars().next();
+        before.map_or(false, |c| c != '/') || after.map_or(false, |c| c != '/' && c != '*')
+    }) {
+        let (before, after) = (&pattern[..i], &pattern[i + 2..]);
+        let before = if before.is_empty() || before.ends_with('/') {
+            before.to_owned()
+        } else {
+            format!("{before}/")
+        };
+        let after = match after {
+            "" => String::new(),
+            after if after.starts_with('/') => after.to_owned(),
+            after => format!("/*{after}"),
+        };
+        return Some(format!("`**` must be a whole path component, so try `{before}**{after}`"));
+    }
+    None
+}
+
+/// Returns the matching pattern with the highest precedence, if any.
+///
+/// Patterns match the full path, where `*` and `?` also match `/`.
+pub(crate) fn find_match<'a>(
+    patterns: &'a [PolicyPattern],
+    path: &str,
+) -> Option<&'a PolicyPattern> {
+    patterns.iter().filter(|p| p.pattern.matches(path)).max_by_key(|p| p.policy)
+}
+
+/// Returns all patterns as a JSON array for the pattern trace.
+pub(crate) fn to_json(patterns: &[PolicyPattern]) -> json::Value {
+    json::Value::Array(
+        patterns
+            .iter()
+            .map(|p| {
+                json::Value::Object(vec![
+                    ("pattern".to_owned(), p.pattern.to_string().into()),
+                    ("policy".to_owned(), p.policy.name().into()),
+                ])
+            })
+            .collect(),
+    )
+}
+
+#[test]
+fn precedence() -> Result<(), glob::PatternError> {
+    let patterns = [
+        PolicyPattern { policy: Policy::Forbid, pattern: Pattern::new("src/**")? },
+        PolicyPattern { policy: Policy::Expect, pattern: Pattern::new("**/*.rs")? },
+        PolicyPattern { policy: Policy::Ignore, pattern: Pattern::new("**/*.bak")? },
+    ];
+    let policy = |path| find_match(&patterns, path).map(|p| p.policy);
+    assert_eq!(policy("src/lib.rs"), Some(Policy::Forbid));
+    assert_eq!(policy("tests/foo.rs"), Some(Policy::Expect));
+    assert_eq!(policy("tests/foo.bak"), Some(Policy::Ignore));
+    assert_eq!(policy("Cargo.toml"), None);
+
+    let patterns = [PolicyPattern { policy: Policy::Ignore, pattern: Pattern::new("*.bak")? }];
+    assert!(find_match(&patterns, "foo.bak").is_some());
+    assert!(find_match(&patterns, "src/foo.bak").is_some());
+    assert!(find_match(&patterns, "src/foo.rs").is_none());
+    Ok(())
+}
+
+#[test]
+fn lints() {
+    let suggestion = |pattern| lint_pattern(pattern).unwrap_or_default();
+    assert!(suggestion("target/").contains("`target/**`"));
+    assert!(suggestion("src\\generated\\*.rs").contains("`src/generated/*.rs`"));
+    assert!(suggestion("./Cargo.lock").contains("`Cargo.lock`"));
+    assert!(suggestion("**.bak").contains("`**/*.bak`"));
+    assert!(suggestion("src**").contains("`src/**`"));
+    assert_eq!(lint_pattern("**/*.bak"), None);
+    assert_eq!(lint_pattern("src/**"), None);
+    assert_eq!(lint_pattern("**"), None);
+    assert_eq!(lint_pattern("Cargo.lock"), None);
+    assert_eq!(lint_pattern("*.bak"), None);
+}
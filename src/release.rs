@@ -0,0 +1,462 @@
+//! Helpers for release automation, e.g. `cargo xtask release`.
+//!
+//! These run git in the same way as [`set_metadata_env_vars`](crate::set_metadata_env_vars),
+//! so the release checks agree with what gets stamped into the binaries.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::patterns::read_policy_patterns;
+use crate::{
+    discover_git_dir, get_workspace_dir, git_dirty, hash, run_git, run_git_predicate,
+    workspace_packages, BuildMetadata,
+};
+
+/// Returns all tags pointing at HEAD, sorted by name.
+pub fn head_tags() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    run_git(&["tag", "--points-at", "HEAD"], |s| -> Result<_, &str> {
+        let mut tags: Vec<String> = s.lines().map(str::to_owned).collect();
+        tags.sort();
+        Ok(tags)
+    })
+}
+
+/// Returns true if the working tree is clean.
+///
+/// Updated paths are checked against `FURIOSA_METADATA_*_MODIFIED` patterns exactly like
+/// the build script does before deciding whether to put `-modified` to the hash.
+pub fn is_tree_clean() -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(!git_dirty(&get_workspace_dir()?, &read_policy_patterns()?)?)
+}
+
+/// Returns the best common ancestor of two revisions, e.g. `merge_base("origin/main", "HEAD")`.
+///
+/// Fails if they have no common ancestor. Like the other ancestry queries, this walks the commits,
+/// which is only fast in a large repository if a commit-graph file has been written
+/// (see [`has_commit_graph`]).
+pub fn merge_base(a: &str, b: &str) -> Result<String, Box<dyn std::error::Error>> {
+    run_git(&["merge-base", a, b], |s| {
+        let s = s.trim_end();
+        hash::is_full_hash(s).then(|| s.to_owned()).ok_or("bad commit id")
+    })
+}
+
+/// Returns the number of commits reachable from HEAD but not from `since`,
+/// e.g. the commits since the last release with `commits_since("v1.2.0")`.
+pub fn commits_since(since: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let range = format!("{since}..HEAD");
+    run_git(&["rev-list", "--count", &range], |s| s.trim_end().parse::<u64>())
+}
+
+/// Returns true if `ancestor` is reachable from `descendant`, including when they are the same.
+pub fn is_ancestor(ancestor: &str, descendant: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    // exits with 1 if not, and with 128 on an error (e.g. an unknown revision)
+    run_git_predicate(&["merge-base", "--is-ancestor", ancestor, descendant])
+}
+
+/// Returns true if the repository has a commit-graph file, without which the ancestry queries
+/// walk every commit and may take seconds in a large repository.
+///
+/// It can be written with `git commit-graph write --reachable`, or on every fetch with
+/// `git config fetch.writeCommitGraph true`.
+///
+/// The commit-graph of an alternate object directory (e.g. of a clone with `--reference`)
+/// counts as well, as git uses it for the commits there.
+pub fn has_commit_graph() -> Result<bool, Box<dyn std::error::Error>> {
+    let git_dir = discover_git_dir()?.ok_or("Not in a git repository")?;
+    let objects = git_dir.common_dir.join("objects");
+    let mut object_dirs = vec![objects.clone()];
+    // one per line, either absolute or relative to the object directory
+    if let Ok(alternates) = fs::read_to_string(objects.join("info/alternates")) {
+        object_dirs.extend(
+            alternates
+                .lines()
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| objects.join(line)),
+        );
+    }
+    Ok(object_dirs.iter().any(|dir| {
+        let info = dir.join("info");
+        info.join("commit-graph").is_file()
+            || info.join("commit-graphs/commit-graph-chain").is_file()
+    }))
+}
+
+/// Returns the components changed between the commit `since` and HEAD, sorted, e.g. to show
+/// "what changed since your version" given the hash of the previously deployed build.
+///
+/// A component is the name of a package in the workspace, or the top-level directory or file
+/// (e.g. `docs` or `Cargo.lock`) of a path outside every package. `since` may be a hash stamped
+/// by this crate, whose `-modified` suffix is ignored.
+pub fn changed_components(since: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let (commit, _) = hash::parse_git_hash(since)?;
+    // paths are relative to the workspace directory, like the package directories
+    let args = ["diff", "--name-only", "--no-renames", "--relative", "-z", commit, "HEAD"];
+    let paths = run_git(&args, |s| {
+        Ok::<_, &str>(s.split_terminator('\0').map(str::to_owned).collect::<Vec<_>>())
+    })?;
+    let packages = workspace_packages()?;
+    let mut components: Vec<String> =
+        paths.iter().map(|path| component(&packages, path).to_owned()).collect();
+    components.sort();
+    components.dedup();
+    Ok(components)
+}
+
+/// Returns the innermost package containing `path`, or its top-level directory or file.
+fn component<'a>(packages: &'a [(String, String)], path: &'a str) -> &'a str {
+    packages
+        .iter()
+        .filter(|(_, dir)| {
+            dir.is_empty() || path.strip_prefix(dir.as_str()).map_or(false, |p| p.starts_with('/'))
+        })
+        .max_by_key(|(_, dir)| dir.len())
+        .map_or_else(|| path.split('/').next().unwrap_or(path), |(name, _)| name)
+}
+
+/// Renders the standard header of release notes in Markdown, so that it always agrees with
+/// the artifacts built with `metadata`, e.g.
+///
+/// ```text
+/// # FuriosaAI SDK 1.2.3
+///
+/// - Version: `1.2.3`
+/// - Commit: `0123456789`
+/// - Build date: 2025-01-07
+/// - Channel: release
+/// - Compatibility:
+///   - furiosa-runtime: `>=1.2, <2`
+/// ```
+///
+/// `compatibility` lists the compatible version ranges of other components, which are omitted
+/// if empty.
+pub fn release_notes_header(
+    product: &str,
+    metadata: &BuildMetadata,
+    compatibility: &[(&str, &str)],
+) -> String {
+    let build_date = metadata.build_timestamp.split('T').next().unwrap_or_default();
+    let mut header = format!(
+        "# {product} {version}\n\n\
+         - Version: `{version}`\n\
+         - Commit: `{hash}`\n\
+         - Build date: {build_date}\n\
+         - Channel: {channel}\n",
+        version = metadata.version,
+        hash = metadata.git_short_hash,
+        channel = metadata.channel,
+    );
+    if !compatibility.is_empty() {
+        header.push_str("- Compatibility:\n");
+        for (component, range) in compatibility {
+            let _ = writeln!(header, "  - {component}: `{range}`");
+        }
+    }
+    header
+}
+
+/// Returns the development version following the release `version`,
+/// which bumps the minor version and adds a `-dev` pre-release, e.g. `1.3.0-dev` for `1.2.4`.
+///
+/// Fails if `version` is not a release version of the form `MAJOR.MINOR.PATCH`.
+pub fn next_dev_version(version: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let parts: Vec<&str> = version.split('.').collect();
+    let numbers: Option<Vec<u64>> = parts
+        .iter()
+        .map(|part| {
+            let canonical = !part.is_empty()
+                && part.bytes().all(|c| c.is_ascii_digit())
+                && (*part == "0" || !part.starts_with('0'));
+            canonical.then(|| part.parse().ok()).flatten()
+        })
+        .collect();
+    match numbers.as_deref() {
+        Some(&[major, minor, _patch]) => Ok(format!("{major}.{}.0-dev", minor + 1)),
+        _ => {
+            Err(format!("{version:?} is not a release version of the form MAJOR.MINOR.PATCH")
+                .into())
+        }
+    }
+}
+
+/// Parses a release tag, `v<version>` or `<package>-v<version>` (e.g. `furiosa-rt-v1.2.3`),
+/// into the package name if any and the version. A tag without a package name is meant for
+/// the root package of the workspace.
+pub fn parse_release_tag(tag: &str) -> Option<(Option<&str>, &str)> {
+    let starts_with_digit = |s: &str| s.starts_with(|c: char| c.is_ascii_digit());
+    if let Some(version) = tag.strip_prefix('v').filter(|version| starts_with_digit(version)) {
+        return Some((None, version));
+    }
+    let (i, _) = tag
+        .match_indices("-v")
+        .filter(|(i, _)| *i > 0 && starts_with_digit(&tag[i + 2..]))
+        .last()?;
+    Some((Some(&tag[..i]), &tag[i + 2..]))
+}
+
+/// Returns why `tag` disagrees with `version` of the package `name`, if it is a release tag of
+/// the package for another version. `root` tells whether the package is the root package of
+/// the workspace.
+///
+/// This is checked for the tags pointing at HEAD while building, and for the pushed tags by
+/// [`check_release_tag`].
+pub(crate) fn tag_mismatch(tag: &str, name: &str, root: bool, version: &str) -> Option<String> {
+    let (package, tag_version) = parse_release_tag(tag)?;
+    let ours = package.map_or(root, |package| package == name);
+    (ours && tag_version != version)
+        .then(|| format!("Tag {tag} disagrees with the version {version} of {name}"))
+}
+
+/// Checks that a release tag (see [`parse_release_tag`]) agrees with the version of its package
+/// at `commit`, where the package is looked up in the current workspace.
+///
+/// Other tags pass, including tags named after a package not in the workspace (e.g.
+/// `nightly-v2` or a tag of another repository), and tags without a package name in a workspace
+/// without a root package.
+pub fn check_release_tag(tag: &str, commit: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let Some((package, _)) = parse_release_tag(tag) else {
+        return Ok(());
+    };
+    let packages = workspace_packages()?;
+    let found = match package {
+        Some(package) => packages.iter().find(|(name, _)| name == package),
+        None => packages.iter().find(|(_, dir)| dir.is_empty()),
+    };
+    let Some((name, dir)) = found else {
+        return Ok(());
+    };
+
+    // `./` makes the path relative to the workspace directory git is run from, which may be
+    // a subdirectory of the repository
+    let manifest = |dir: &str| {
+        let path =
+            if dir.is_empty() { "./Cargo.toml".to_owned() } else { format!("./{dir}/Cargo.toml") };
+        run_git(&["show", &format!("{commit}^{{commit}}:{path}")], |s| Ok::<_, &str>(s.to_owned()))
+    };
+    let package_manifest = manifest(dir)?;
+    let version = match manifest_version(&package_manifest, "package") {
+        Some(version) => version.to_owned(),
+        None => manifest_version(&manifest("")?, "workspace.package")
+            .ok_or_else(|| format!("No version of {name} at {commit}"))?
+            .to_owned(),
+    };
+    match tag_mismatch(tag, name, dir.is_empty(), &version) {
+        Some(mismatch) => Err(format!("{mismatch} at {commit}").into()),
+        None => Ok(()),
+    }
+}
+
+/// Returns the literal `version` in `[table]` of a manifest, or `None` if there is none,
+/// e.g. inherited with `version.workspace = true`.
+fn manifest_version<'a>(manifest: &'a str, table: &str) -> Option<&'a str> {
+    let mut in_table = false;
+    for line in manifest.lines().map(str::trim) {
+        if let Some(header) = line.strip_prefix('[') {
+            in_table = header.split(']').next().map(str::trim) == Some(table);
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if in_table && key.trim() == "version" {
+            let value = value.trim();
+            return value.strip_prefix('"')?.split('"').next();
+        }
+    }
+    None
+}
+
+/// Returns the tags being pushed, with their objects, from the standard input of a pre-push hook,
+/// which has a `<local ref> <local object> <remote ref> <remote object>` line for each ref.
+/// Deleted tags are skipped.
+pub fn pushed_tags(input: &str) -> Vec<(&str, &str)> {
+    input
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (local_ref, object) = (fields.next()?, fields.next()?);
+            let tag = local_ref.strip_prefix("refs/tags/")?;
+            (!object.bytes().all(|c| c == b'0')).then_some((tag, object))
+        })
+        .collect()
+}
+
+const HOOK_MARKER: &str = "# installed by `furiosa-metadata install-hooks`";
+
+/// Installs a pre-push hook which checks the pushed release tags with
+/// `furiosa-metadata check-tags`, i.e. [`check_release_tag`], so that a tag disagreeing with
+/// the version is rejected before CI. Returns the path of the hook.
+///
+/// Fails if there is already a pre-push hook not installed by this.
+pub fn install_pre_push_hook() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    // respects `core.hooksPath`, and is relative to the workspace directory git is run from
+    let hooks_dir = run_git(&["rev-parse", "--git-path", "hooks"], |s| {
+        Ok::<_, &str>(PathBuf::from(s.trim_end()))
+    })?;
+    let workspace_dir = get_workspace_dir()?;
+    let hooks_dir = Path::new(&workspace_dir).join(hooks_dir);
+    let path = hooks_dir.join("pre-push");
+    match fs::read_to_string(&path) {
+        Ok(hook) if !hook.contains(HOOK_MARKER) => {
+            return Err(format!(
+                "{} already exists; add `furiosa-metadata check-tags` to it instead",
+                path.display()
+            )
+            .into())
+        }
+        _ => {}
+    }
+
+    // git runs hooks from the top level of the repository, where the workspace may not be
+    let hook = format!(
+        "#!/bin/sh\n\
+         {HOOK_MARKER}\n\
+         cd {workspace_dir} || exit 1\n\
+         if ! command -v furiosa-metadata >/dev/null 2>&1; then\n\
+         \x20   echo 'furiosa-metadata is not found, skipping the release tag checks' >&2\n\
+         \x20   exit 0\n\
+         fi\n\
+         exec furiosa-metadata check-tags\n",
+        workspace_dir = shell_quote(&workspace_dir)
+    );
+    fs::create_dir_all(&hooks_dir)?;
+    fs::write(&path, hook)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(path)
+}
+
+/// Quotes `s` for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[test]
+fn dev_versions() {
+    assert_eq!(next_dev_version("1.2.4").unwrap(), "1.3.0-dev");
+    assert_eq!(next_dev_version("0.0.0").unwrap(), "0.1.0-dev");
+    assert!(next_dev_version("1.2.4-rc.1").is_err());
+    assert!(next_dev_version("1.2").is_err());
+    assert!(next_dev_version("1.02.3").is_err());
+}
+
+#[test]
+fn release_notes() {
+    let metadata = BuildMetadata::__new(
+        "1.2.3",
+        "0123456789",
+        "0123456789abcdef0123456789abcdef01234567",
+        "main",
+        "v1.2.3",
+        "2025-01-06T10:00:00Z",
+        "false",
+        "2025-01-07T10:00:00Z",
+        "release",
+    );
+    assert_eq!(
+        release_notes_header("FuriosaAI SDK", &metadata, &[("furiosa-runtime", ">=1.2, <2")]),
+        "# FuriosaAI SDK 1.2.3\n\n\
+         - Version: `1.2.3`\n\
+         - Commit: `0123456789`\n\
+         - Build date: 2025-01-07\n\
+         - Channel: release\n\
+         - Compatibility:\n  \
+         - furiosa-runtime: `>=1.2, <2`\n"
+    );
+    assert!(release_notes_header("FuriosaAI SDK", &metadata, &[]).ends_with("release\n"));
+}
+
+#[test]
+fn tree_status() -> Result<(), Box<dyn std::error::Error>> {
+    head_tags()?;
+    is_tree_clean()?;
+    Ok(())
+}
+
+#[test]
+fn ancestry() -> Result<(), Box<dyn std::error::Error>> {
+    assert_eq!(commits_since("HEAD")?, 0);
+    assert!(is_ancestor("HEAD", "HEAD")?);
+    assert!(is_ancestor("no-such-revision", "HEAD").is_err());
+    let parent = run_git(&["rev-parse", "--verify", "--quiet", "HEAD~1"], |s| {
+        Ok::<_, &str>(s.trim_end().to_owned())
+    });
+    // not in a shallow clone of a single commit
+    if let Ok(parent) = parent {
+        assert!(is_ancestor(&parent, "HEAD")?);
+        assert!(!is_ancestor("HEAD", &parent)?);
+    }
+    assert!(hash::is_full_hash(&merge_base("HEAD", "HEAD")?));
+    Ok(())
+}
+
+#[test]
+fn components() -> Result<(), Box<dyn std::error::Error>> {
+    let packages = [
+        ("runtime".to_owned(), "crates/runtime".to_owned()),
+        ("runtime-sys".to_owned(), "crates/runtime/sys".to_owned()),
+        ("cli".to_owned(), "crates/cli".to_owned()),
+    ];
+    assert_eq!(component(&packages, "crates/runtime/src/lib.rs"), "runtime");
+    assert_eq!(component(&packages, "crates/runtime/sys/build.rs"), "runtime-sys");
+    assert_eq!(component(&packages, "crates/runtime-old/lib.rs"), "crates");
+    assert_eq!(component(&packages, "Cargo.lock"), "Cargo.lock");
+
+    let root = [("sdk".to_owned(), String::new()), ("cli".to_owned(), "cli".to_owned())];
+    assert_eq!(component(&root, "cli/src/main.rs"), "cli");
+    assert_eq!(component(&root, "docs/index.md"), "sdk");
+
+    assert!(changed_components("HEAD").is_err());
+    let head = run_git(&["rev-parse", "HEAD"], |s| Ok::<_, &str>(s.trim_end().to_owned()))?;
+    assert!(changed_components(&head)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn release_tags() {
+    assert_eq!(parse_release_tag("v1.2.3"), Some((None, "1.2.3")));
+    assert_eq!(
+        parse_release_tag("furiosa-rt-v1.2.3-rc.1"),
+        Some((Some("furiosa-rt"), "1.2.3-rc.1"))
+    );
+    assert_eq!(parse_release_tag("dev-vm-v0.1.0"), Some((Some("dev-vm"), "0.1.0")));
+    assert_eq!(parse_release_tag("vnext"), None);
+    assert_eq!(parse_release_tag("nightly-20250107"), None);
+
+    assert_eq!(tag_mismatch("v1.2.3", "sdk", true, "1.2.3"), None);
+    assert!(tag_mismatch("v1.2.3", "sdk", true, "1.2.4").is_some());
+    assert_eq!(tag_mismatch("v1.2.3", "rt", false, "1.2.4"), None);
+    assert!(tag_mismatch("rt-v1.2.3", "rt", false, "1.2.4").is_some());
+    assert_eq!(tag_mismatch("rt-v1.2.3", "sdk", true, "1.2.4"), None);
+
+    let manifest = "[package]\nname = \"rt\"\nversion = \"1.2.3\" # bumped\n\n\
+                    [dependencies]\nfoo = { version = \"1\" }\n";
+    assert_eq!(manifest_version(manifest, "package"), Some("1.2.3"));
+    assert_eq!(manifest_version("[package]\nversion.workspace = true\n", "package"), None);
+    let workspace = "[workspace.package]\nversion = \"2.0.0\"\n";
+    assert_eq!(manifest_version(workspace, "workspace.package"), Some("2.0.0"));
+
+    let input = "refs/tags/v1.2.3 1111111111111111111111111111111111111111 refs/tags/v1.2.3 \
+                 0000000000000000000000000000000000000000\n\
+                 refs/heads/main 2222222222222222222222222222222222222222 refs/heads/main \
+                 3333333333333333333333333333333333333333\n\
+                 (delete) 0000000000000000000000000000000000000000 refs/tags/v1.2.2 \
+                 4444444444444444444444444444444444444444\n";
+    assert_eq!(pushed_tags(input), [("v1.2.3", "1111111111111111111111111111111111111111")]);
+}
+
+#[test]
+fn release_tag_checks() -> Result<(), Box<dyn std::error::Error>> {
+    // not a release tag of this workspace, whatever the commit is
+    check_release_tag("nightly-v2", "0000000")?;
+    check_release_tag("furiosa-metadata-next-v1", "0000000")?;
+    check_release_tag("latest", "0000000")?;
+    let mismatch = check_release_tag("furiosa-metadata-v0.0.1", "HEAD").map_err(|e| e.to_string());
+    assert!(mismatch.unwrap_err().contains("disagrees with the version"));
+    assert_eq!(shell_quote("/src/it's here"), r"'/src/it'\''s here'");
+    Ok(())
+}
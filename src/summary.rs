@@ -0,0 +1,203 @@
+//! The summary of all crates stamped in a target directory,
+//! `<target dir>/furiosa-metadata/summary.json`, to be archived with the build.
+
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{invocation_id, json, short_digest, target_dir, write_atomically};
+
+const SUMMARY_FILE_NAME: &str = "summary.json";
+
+/// Records the variables set for the current crate, and regenerates the summary from the records
+/// of the current build.
+///
+/// The summary is a JSON object with a `crates` list, each with `package`, `version`, `out_dir`,
+/// `invocation_id`, `sources` (parsed from `FURIOSA_METADATA_SOURCE`) and `vars`.
+/// It only lists the crates built into the same profile directory (e.g. `target/release`),
+/// and a crate stamped again replaces its records from the previous invocations, while crates
+/// not rebuilt in the current invocation are kept.
+pub(crate) fn update_summary(vars: &[(String, String)]) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(out_dir) = env::var_os("OUT_DIR") else {
+        return Ok(());
+    };
+    let out_dir = out_dir.to_string_lossy().into_owned();
+    let package = env::var("CARGO_PKG_NAME")?;
+    let invocation_id = invocation_id();
+
+    let var = |name: &str| vars.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+    let sources = var("FURIOSA_METADATA_SOURCE")
+        .unwrap_or_default()
+        .split(';')
+        .filter_map(|source| source.split_once('='))
+        .map(|(field, source)| (field.to_owned(), source.into()))
+        .collect();
+    let record = json::Value::Object(vec![
+        ("package".to_owned(), package.as_str().into()),
+        ("version".to_owned(), env::var("CARGO_PKG_VERSION").ok().into()),
+        ("out_dir".to_owned(), out_dir.as_str().into()),
+        ("invocation_id".to_owned(), invocation_id.as_deref().into()),
+        ("sources".to_owned(), json::Value::Object(sources)),
+        (
+            "vars".to_owned(),
+            json::Value::Object(
+                vars.iter().map(|(name, value)| (name.clone(), value.as_str().into())).collect(),
+            ),
+        ),
+    ]);
+
+    let dir = target_dir()?.join("furiosa-metadata");
+    let crates_dir = dir.join("crates");
+    fs::create_dir_all(&crates_dir)?;
+    // the same package is built into different `OUT_DIR`s for each profile, target and features
+    let record_name = format!("{package}-{}.json", short_digest(&[&out_dir]));
+    write_atomically(&crates_dir.join(record_name), &record.to_string())?;
+
+    // build scripts run in parallel, and each must see the records written before
+    let _lock = Lock::acquire(&dir.join("summary.lock"))?;
+    let records = read_records(&crates_dir, profile_dir(&out_dir), invocation_id.as_deref())?;
+    let summary = json::Value::Object(vec![("crates".to_owned(), json::Value::Array(records))]);
+    write_atomically(&dir.join(SUMMARY_FILE_NAME), &summary.to_string())?;
+    Ok(())
+}
+
+/// Returns the profile directory of an `OUT_DIR`, i.e. `<profile dir>/build/<package>-<hash>/out`.
+fn profile_dir(out_dir: &str) -> Option<&Path> {
+    Path::new(out_dir).ancestors().nth(3)
+}
+
+/// Reads the records of `profile_dir` sorted by the file name.
+///
+/// Records whose `OUT_DIR` is gone (e.g. by `cargo clean -p`), and records of a package from
+/// other invocations than `invocation_id` if the package has been stamped in it, are removed.
+fn read_records(
+    crates_dir: &Path,
+    profile_dir: Option<&Path>,
+    invocation_id: Option<&str>,
+) -> Result<Vec<json::Value>, Box<dyn std::error::Error>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(crates_dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.extension().map_or(false, |ext| ext == "json"));
+    paths.sort();
+
+    let mut records = Vec::new();
+    for path in paths {
+        let record = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|record| json::parse(&record))
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        match record.get("out_dir").and_then(json::Value::as_str) {
+            Some(out_dir) if Path::new(out_dir).is_dir() => {
+                if self::profile_dir(out_dir) == profile_dir {
+                    records.push((path, record));
+                }
+            }
+            _ => fs::remove_file(&path)?,
+        }
+    }
+
+    let stamped: Vec<&str> = records
+        .iter()
+        .filter(|(_, record)| {
+            invocation_id.is_some() && field(record, "invocation_id") == invocation_id
+        })
+        .filter_map(|(_, record)| field(record, "package"))
+        .collect();
+    let (current, replaced): (Vec<_>, Vec<_>) = records.iter().partition(|(_, record)| {
+        field(record, "invocation_id") == invocation_id
+            || !field(record, "package").map_or(false, |package| stamped.contains(&package))
+    });
+    for (path, _) in replaced {
+        fs::remove_file(path)?;
+    }
+    Ok(current.into_iter().map(|(_, record)| record.clone()).collect())
+}
+
+fn field<'a>(record: &'a json::Value, key: &str) -> Option<&'a str> {
+    record.get(key).and_then(json::Value::as_str)
+}
+
+/// A lock held while the directory exists, as creating a directory is atomic everywhere.
+struct Lock(PathBuf);
+
+impl Lock {
+    /// A lock older than this is considered left behind by a killed build script.
+    const STALE_AFTER: Duration = Duration::from_secs(30);
+
+    fn acquire(path: &Path) -> Result<Lock, Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        loop {
+            match fs::create_dir(path) {
+                Ok(()) => return Ok(Lock(path.to_owned())),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(format!("Failed to lock {}: {e}", path.display()).into()),
+            }
+
+            let created = fs::metadata(path).and_then(|metadata| metadata.modified());
+            let age =
+                created.ok().and_then(|created| SystemTime::now().duration_since(created).ok());
+            if age.map_or(false, |age| age > Self::STALE_AFTER) {
+                let _ = fs::remove_dir(path);
+            } else if start.elapsed() > Self::STALE_AFTER {
+                return Err(format!("Timed out waiting for the lock {}", path.display()).into());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir(&self.0);
+    }
+}
+
+#[test]
+fn records() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = env::temp_dir().join(format!("furiosa-metadata-summary-{}", std::process::id()));
+    let crates_dir = dir.join("crates");
+    fs::create_dir_all(&crates_dir)?;
+    let out_dir = |profile: &str, package: &str| -> Result<PathBuf, std::io::Error> {
+        let out_dir = dir.join(profile).join("build").join(package).join("out");
+        fs::create_dir_all(&out_dir)?;
+        Ok(out_dir)
+    };
+    let record = |package: &str, out_dir: &Path, invocation_id: &str| {
+        json::Value::Object(vec![
+            ("package".to_owned(), package.into()),
+            ("out_dir".to_owned(), out_dir.to_string_lossy().into_owned().into()),
+            ("invocation_id".to_owned(), invocation_id.into()),
+        ])
+        .to_string()
+    };
+    let release = out_dir("release", "a-1")?;
+    fs::write(crates_dir.join("b-1.json"), record("b", &out_dir("release", "b-1")?, "old"))?;
+    fs::write(crates_dir.join("a-1.json"), record("a", &release, "new"))?;
+    // rebuilt with other features in the current invocation
+    fs::write(crates_dir.join("a-2.json"), record("a", &out_dir("release", "a-2")?, "old"))?;
+    fs::write(crates_dir.join("c-1.json"), record("c", &dir.join("gone"), "old"))?;
+    fs::write(crates_dir.join("d-1.json"), record("d", &out_dir("debug", "d-1")?, "old"))?;
+
+    let lock = Lock::acquire(&dir.join("summary.lock"))?;
+    let records = read_records(&crates_dir, profile_dir(&release.to_string_lossy()), Some("new"))?;
+    let locked = dir.join("summary.lock").is_dir();
+    drop(lock);
+    let unlocked = !dir.join("summary.lock").exists();
+    let exists = |name: &str| crates_dir.join(name).exists();
+    let (a_2, c, d) = (exists("a-2.json"), exists("c-1.json"), exists("d-1.json"));
+    fs::remove_dir_all(&dir)?;
+
+    let packages: Vec<_> =
+        records.iter().filter_map(|record| record.get("package")?.as_str()).collect();
+    assert_eq!(packages, ["a", "b"]);
+    assert!(!a_2);
+    assert!(!c);
+    assert!(d);
+    assert!(locked);
+    assert!(unlocked);
+    Ok(())
+}
@@ -0,0 +1,82 @@
+//! Helpers for tuning `FURIOSA_METADATA_EXPECT_MODIFIED` against the current working tree,
+//! e.g. `furiosa-metadata patterns --interactive`.
+//!
+//! Patterns are matched exactly like the dirty repository detection of
+//! [`set_metadata_env_vars`](crate::set_metadata_env_vars).
+
+use glob::Pattern;
+
+use crate::patterns::{self, lint_pattern, read_policy_patterns, Policy, PolicyPattern};
+use crate::{get_workspace_dir, git_updated_paths};
+
+/// Returns the paths updated in the working tree, which make the repository dirty
+/// unless matched by a pattern.
+pub fn updated_paths() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    git_updated_paths(&get_workspace_dir()?)
+}
+
+/// Returns the patterns currently in `FURIOSA_METADATA_EXPECT_MODIFIED`.
+pub fn expected_patterns() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(read_policy_patterns()?
+        .into_iter()
+        .filter(|p| p.policy == Policy::Expect)
+        .map(|p| p.pattern.as_str().to_owned())
+        .collect())
+}
+
+/// Returns the paths matching `pattern`.
+///
+/// Fails if `pattern` is invalid, with a suggestion for common mistakes.
+pub fn matching_paths<'a>(
+    pattern: &str,
+    paths: &'a [String],
+) -> Result<Vec<&'a str>, Box<dyn std::error::Error>> {
+    if pattern.is_empty() || pattern.contains(':') {
+        return Err(format!("{pattern:?} is empty or contains `:`, the pattern separator").into());
+    }
+    let pattern = Pattern::new(pattern).map_err(|e| match lint_pattern(pattern) {
+        Some(suggestion) => format!("{e}; {suggestion}"),
+        None => e.to_string(),
+    })?;
+    let patterns = [PolicyPattern { policy: Policy::Expect, pattern }];
+    Ok(paths
+        .iter()
+        .map(String::as_str)
+        .filter(|path| patterns::find_match(&patterns, path).is_some())
+        .collect())
+}
+
+/// Returns a suggestion if `pattern` looks like a common mistake, e.g. `target/` for `target/**`.
+pub fn suggestion(pattern: &str) -> Option<String> {
+    lint_pattern(pattern)
+}
+
+/// Renders `FURIOSA_METADATA_EXPECT_MODIFIED` with `patterns`, both for a shell and
+/// for the `[env]` table of `.cargo/config.toml`.
+pub fn expect_modified_snippet(patterns: &[String]) -> String {
+    let var = Policy::Expect.var();
+    let value = patterns.join(":");
+    let shell = value.replace('\'', r"'\''");
+    let toml = value.replace('\\', r"\\").replace('"', r#"\""#);
+    format!("export {var}='{shell}'\n\n# .cargo/config.toml\n[env]\n{var} = \"{toml}\"\n")
+}
+
+#[test]
+fn tuning() -> Result<(), Box<dyn std::error::Error>> {
+    let paths = ["Cargo.lock".to_owned(), "src/lib.rs".to_owned(), "src/gen/a.rs".to_owned()];
+    assert_eq!(matching_paths("src/**/*.rs", &paths)?, ["src/lib.rs", "src/gen/a.rs"]);
+    assert_eq!(matching_paths("src/*.rs", &paths)?, ["src/lib.rs", "src/gen/a.rs"]);
+    assert_eq!(matching_paths("*.lock", &paths)?, ["Cargo.lock"]);
+    assert!(matching_paths("gen/*", &paths)?.is_empty());
+    assert!(matching_paths("a:b", &paths).is_err());
+    assert!(matching_paths("", &paths).is_err());
+
+    assert_eq!(
+        expect_modified_snippet(&["Cargo.lock".to_owned(), "src/gen/**".to_owned()]),
+        "export FURIOSA_METADATA_EXPECT_MODIFIED='Cargo.lock:src/gen/**'\n\n\
+         # .cargo/config.toml\n\
+         [env]\n\
+         FURIOSA_METADATA_EXPECT_MODIFIED = \"Cargo.lock:src/gen/**\"\n"
+    );
+    Ok(())
+}